@@ -1,10 +1,14 @@
 use std::ops::Deref;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use clearurl::UrlCleaner;
 use deepl::DeepLApi;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
-use crate::{cache::Cacher, http::HttpClient};
+use crate::{
+    cache::Cacher, config::Config, http::HttpClient, modules::download_queue::DownloadQueue,
+};
 
 pub struct AppData(Arc<RuntimeData>);
 
@@ -32,10 +36,82 @@ impl Deref for AppData {
 pub struct RuntimeData {
     pub cacher: Cacher,
     pub requester: HttpClient,
-
-    pub deepl: DeepLApi,
+    /// Clients with the proxies in the config file, replaced when the config is reloaded
+    #[builder(setter(transform = |clients: ProxiedClients| RwLock::new(clients)))]
+    proxied: RwLock<ProxiedClients>,
 
     pub quote_maker: make_quote::QuoteProducer<'static>,
 
     pub url_cleaner: UrlCleaner,
+
+    pub download_queue: DownloadQueue,
+
+    /// Cancelled when the bot is shutting down
+    pub shutdown: CancellationToken,
+    /// Background tasks that should be finished before the bot exits
+    #[builder(default)]
+    pub tasks: TaskTracker,
+}
+
+impl RuntimeData {
+    fn proxied(&self) -> std::sync::RwLockReadGuard<'_, ProxiedClients> {
+        self.proxied.read().expect("proxied clients poisoned")
+    }
+
+    /// HTTP client to access the bilibili API
+    pub fn bilibili_client(&self) -> HttpClient {
+        let proxied = self.proxied();
+        proxied.bilibili.as_ref().unwrap_or(&self.requester).clone()
+    }
+
+    /// HTTP client to access the Twitch API
+    pub fn twitch_client(&self) -> HttpClient {
+        let proxied = self.proxied();
+        proxied.twitch.as_ref().unwrap_or(&self.requester).clone()
+    }
+
+    /// HTTP client to access YouTube
+    pub fn youtube_client(&self) -> HttpClient {
+        let proxied = self.proxied();
+        proxied.youtube.as_ref().unwrap_or(&self.requester).clone()
+    }
+
+    pub fn deepl(&self) -> DeepLApi {
+        self.proxied().deepl.clone()
+    }
+
+    /// Use the clients rebuilt from the reloaded config for the following requests
+    pub fn set_proxied_clients(&self, clients: ProxiedClients) {
+        *self.proxied.write().expect("proxied clients poisoned") = clients;
+    }
+}
+
+/// Clients depending on the proxy settings, `None` if no proxy is configured for the service
+pub struct ProxiedClients {
+    pub bilibili: Option<HttpClient>,
+    pub twitch: Option<HttpClient>,
+    pub youtube: Option<HttpClient>,
+    pub deepl: DeepLApi,
+}
+
+impl ProxiedClients {
+    pub fn from_config(cfg: &Config) -> anyhow::Result<Self> {
+        let proxied = |proxy_url: Option<&str>| proxy_url.map(HttpClient::with_proxy).transpose();
+
+        let mut deepl = DeepLApi::with(&cfg.deepl.api_key);
+        if let Some(proxy_url) = cfg.proxy.deepl() {
+            let client = reqwest::Client::builder()
+                .proxy(reqwest::Proxy::all(proxy_url)?)
+                .timeout(Duration::from_secs(30))
+                .build()?;
+            deepl.client(client);
+        }
+
+        Ok(Self {
+            bilibili: proxied(cfg.proxy.bilibili())?,
+            twitch: proxied(cfg.proxy.twitch())?,
+            youtube: proxied(cfg.proxy.youtube())?,
+            deepl: deepl.new(),
+        })
+    }
 }
@@ -2,6 +2,7 @@ use anyhow::Result;
 use image::ImageFormat;
 use rand::Rng;
 use redis::Commands;
+use serde::{Deserialize, Serialize};
 use std::fmt::Write;
 use teloxide::{
     dispatching::{dialogue, UpdateHandler},
@@ -9,15 +10,24 @@ use teloxide::{
     payloads::SendPhotoSetters,
     prelude::*,
     types::{
-        ChatKind, InlineKeyboardButton, InlineKeyboardMarkup, InputFile, InputSticker, ParseMode,
-        User,
+        Chat, ChatKind, InlineKeyboardButton, InlineKeyboardMarkup, InputFile, InputMedia,
+        InputMediaAudio, InputMediaPhoto, InputMediaVideo, InputSticker, MessageId, ParseMode,
+        ReplyParameters, StickerFormat, User,
     },
     utils::command::BotCommands,
 };
 
 use rusty_maid::{
     app::AppData,
-    modules::{self, price::PriceInfo, Sendable},
+    config::Config,
+    modules::{
+        self,
+        download_queue::DownloadBatch,
+        price::PriceInfo,
+        video_dl::{DownloadOptions, MediaFile, MediaKind, RegistryMedia, VideoDownloader},
+        ytd::{DownloadMode, DownloadProgress, YtdlpPlaylist},
+        Sendable,
+    },
     sendable,
 };
 
@@ -76,8 +86,19 @@ macro_rules! generate_commands {
         }
 
         paste::paste! {
+            impl Command {
+                /// Command name used in the metrics
+                fn name(&self) -> &'static str {
+                    match self {
+                        $( Command::$cmd => stringify!([< $cmd:lower >]), )+
+                        $( Command::$scmd => stringify!([< $scmd:lower >]), )+
+                    }
+                }
+            }
+
             fn generate_stateless_cmd_handler() -> UpdateHandler<anyhow::Error>  {
                 teloxide::filter_command::<Command, _>()
+                    .chain(dptree::from_fn(record_command_metrics))
                     $(
                         .branch(
                             dptree::case![Command::$cmd]
@@ -121,8 +142,26 @@ generate_commands! {
         MakeQuote,
         #[desc = "Delete a sticker create by this bot"]
         DelSticker,
-        #[desc = "Download video through yt-dlp"]
+        #[desc = "Reply to a photo, image, GIF, video or sticker to add it into the sticker set. Usage: /sticker [emoji]"]
+        Sticker,
+        #[desc = "Download video through yt-dlp. Use /ytdlp -a <url> to download audio only, /ytdlp <url> p1-3 to select playlist items, /ytdlp cancel to cancel your task"]
         Ytdlp,
+        #[desc = "Automatically download bilibili, YouTube shorts and Twitter videos in this chat. Usage: /autodownload [on|off]"]
+        AutoDownload,
+        #[desc = "Subscribe RSS or Atom feeds in this chat. Usage: /rss add <url>, /rss list, /rss del <id>"]
+        Rss,
+        #[desc = "Watch a GitHub repository in this chat. Usage: /ghwatch owner/repo [releases|tags|commits], /ghwatch del owner/repo [kind], /ghwatch to list"]
+        Ghwatch,
+        #[desc = "Show the most used commands and the most active users in this chat. Usage: /stats [7d|30d], /stats [on|off] to record the usage or not"]
+        Stats,
+        #[desc = "(Bot admins only) Send the text to every chat with subscriptions. Usage: /broadcast <text>"]
+        Broadcast,
+        #[desc = "(Bot admins only) Reload the config file without restarting the bot"]
+        Reload,
+        #[desc = "(Bot admins only) List the chats known by the bot"]
+        Chats,
+        #[desc = "(Bot admins only) Show Redis usage. Usage: /redis info"]
+        Redis,
     }
     stateful: {
         #[desc = "Finish Collect"]
@@ -130,6 +169,37 @@ generate_commands! {
     }
 }
 
+/// Count the invocation and measure the latency of the command handled by the following handlers
+async fn record_command_metrics(
+    deps: dptree::di::DependencyMap,
+    cont: dptree::Cont<'static, dptree::di::DependencyMap, anyhow::Result<()>>,
+) -> std::ops::ControlFlow<anyhow::Result<()>, dptree::di::DependencyMap> {
+    use dptree::di::DependencySupplier;
+    use std::ops::ControlFlow;
+
+    let command: std::sync::Arc<Command> = deps.get();
+    let msg: std::sync::Arc<Message> = deps.get();
+    let data: std::sync::Arc<AppData> = deps.get();
+    let recorded =
+        modules::stats::record_command(&data, msg.chat.id.0, msg.from.as_ref(), command.name());
+    if let Err(err) = recorded {
+        tracing::error!("fail to record command usage: {err}");
+    }
+    if let Err(err) = modules::admin::record_known_chat(&data, &msg.chat) {
+        tracing::error!("fail to record known chat: {err}");
+    }
+
+    let start = std::time::Instant::now();
+    let result = cont(deps).await;
+    if let ControlFlow::Break(handled) = &result {
+        rusty_maid::metrics::record_command(command.name(), start.elapsed(), handled.is_ok());
+        if let Err(err) = handled {
+            rusty_maid::metrics::record_upstream_error(err);
+        }
+    }
+    result
+}
+
 macro_rules! send_action {
     (@$action:ident; $msg:ident, $bot:ident) => {
         $bot.send_chat_action($msg.chat.id, teloxide::types::ChatAction::$action)
@@ -171,7 +241,10 @@ pub fn handler_schema() -> UpdateHandler<anyhow::Error> {
 
     let callback_handler = Update::filter_callback_query().endpoint(callback_dispatcher);
 
-    let root = dptree::entry().branch(msg_handler).branch(callback_handler);
+    let root = dptree::entry()
+        .inspect(rusty_maid::metrics::record_telegram_update)
+        .branch(msg_handler)
+        .branch(callback_handler);
 
     dialogue::enter::<Update, dialogue::InMemStorage<DialogueStatus>, DialogueStatus, _>()
         .branch(root)
@@ -189,13 +262,20 @@ async fn plain_message_handler(msg: Message, bot: Bot, app_data: AppData) -> any
         .collect();
 
     if urls.is_empty() {
+        // BV id can be posted without the link
+        send_bilibili_preview(&msg, &bot, &app_data).await;
         return Ok(());
     }
 
     let mut data = Vec::new();
+    let mut auto_download_url = None;
 
     for url in urls {
         if let Ok(result) = app_data.url_cleaner.clear(url).await {
+            if auto_download_url.is_none() && modules::ytd::is_auto_download_url(&result) {
+                auto_download_url = Some(result.clone());
+            }
+
             if result.as_str() == url {
                 continue;
             }
@@ -204,6 +284,20 @@ async fn plain_message_handler(msg: Message, bot: Bot, app_data: AppData) -> any
         }
     }
 
+    let auto_downloading = match auto_download_url {
+        Some(url) => auto_download(&msg, bot.clone(), app_data.clone(), url)
+            .await
+            .unwrap_or_else(|err| {
+                tracing::warn!("[ytdlp] fail to start auto download: {err}");
+                false
+            }),
+        None => false,
+    };
+    // The downloaded video comes with the caption, no need to preview it again
+    if !auto_downloading {
+        send_bilibili_preview(&msg, &bot, &app_data).await;
+    }
+
     if !data.is_empty() {
         bot.send_message(
             msg.chat.id,
@@ -221,6 +315,410 @@ async fn plain_message_handler(msg: Message, bot: Bot, app_data: AppData) -> any
     Ok(())
 }
 
+const YTDLP_AUTO_DOWNLOAD_CHATS: &str = "YTDLP_AUTO_DOWNLOAD_CHATS";
+
+async fn auto_download_handler(msg: Message, bot: Bot, data: AppData) -> anyhow::Result<()> {
+    let text = msg.text().expect("Unreachable");
+    let enable = match text.split_whitespace().nth(1) {
+        Some("on") => true,
+        Some("off") => false,
+        Some(_) => {
+            abort!(bot, msg, "Usage: /autodownload [on|off]");
+        }
+        None => {
+            let enabled: bool = data
+                .cacher
+                .get_conn()
+                .sismember(YTDLP_AUTO_DOWNLOAD_CHATS, msg.chat.id.0)?;
+            abort!(
+                bot,
+                msg,
+                "Auto download is {} in this chat",
+                if enabled { "enabled" } else { "disabled" }
+            );
+        }
+    };
+
+    let Some(requester) = msg.from.as_ref() else {
+        abort!(bot, msg, "Can't identify who are you");
+    };
+    if !is_chat_admin(&msg.chat, requester, bot.clone()).await {
+        abort!(
+            bot,
+            msg,
+            "Only the chat administrators can change this setting"
+        );
+    }
+
+    let mut redis_cli = data.cacher.get_conn();
+    if enable {
+        let () = redis_cli.sadd(YTDLP_AUTO_DOWNLOAD_CHATS, msg.chat.id.0)?;
+        abort!(
+            bot,
+            msg,
+            "Auto download enabled, bilibili, YouTube shorts and Twitter videos will be downloaded"
+        );
+    }
+    let () = redis_cli.srem(YTDLP_AUTO_DOWNLOAD_CHATS, msg.chat.id.0)?;
+    abort!(bot, msg, "Auto download disabled");
+}
+
+async fn rss_handler(msg: Message, bot: Bot, data: AppData) -> anyhow::Result<()> {
+    const USAGE: &str = "Usage: /rss add <url>, /rss list, /rss del <id>";
+    let text = msg.text().expect("Unreachable");
+    let mut args = text.split_whitespace().skip(1);
+    let chat_id = msg.chat.id.0;
+
+    let action = args.next();
+    if matches!(action, None | Some("list")) {
+        let feeds = modules::rss::list_feeds(&data, chat_id)?;
+        if feeds.is_empty() {
+            abort!(bot, msg, "No feed subscribed in this chat. {USAGE}");
+        }
+        let list = feeds
+            .iter()
+            .enumerate()
+            .fold(String::new(), |mut acc, (i, url)| {
+                writeln!(&mut acc, "{}. {url}", i + 1).unwrap();
+                acc
+            });
+        abort!(bot, msg, "{list}");
+    }
+
+    let Some(requester) = msg.from.as_ref() else {
+        abort!(bot, msg, "Can't identify who are you");
+    };
+    if !is_chat_admin(&msg.chat, requester, bot.clone()).await {
+        abort!(
+            bot,
+            msg,
+            "Only the chat administrators can manage the feeds"
+        );
+    }
+
+    match (action, args.next()) {
+        (Some("add"), Some(url)) => {
+            send_action!(@Typing; msg, bot);
+            if let Err(err) = modules::rss::check_feed_url(url).await {
+                abort!(bot, msg, "Invalid URL {url}: {err}");
+            }
+            match modules::rss::subscribe_feed(&data, chat_id, url).await {
+                Ok(Some(title)) => {
+                    abort!(bot, msg, "Subscribed to {title}");
+                }
+                Ok(None) => {
+                    abort!(bot, msg, "Subscribed to {url}");
+                }
+                Err(err) => {
+                    abort!(bot, msg, "Fail to subscribe the feed: {err}");
+                }
+            }
+        }
+        (Some("del"), Some(id)) => {
+            let feeds = modules::rss::list_feeds(&data, chat_id)?;
+            let Some(url) = id
+                .parse::<usize>()
+                .ok()
+                .and_then(|id| feeds.get(id.checked_sub(1)?))
+            else {
+                abort!(bot, msg, "Feed {id} not found, check the id in /rss list");
+            };
+            modules::rss::unsubscribe_feed(&data, chat_id, url)?;
+            abort!(bot, msg, "Unsubscribed {url}");
+        }
+        _ => {
+            abort!(bot, msg, "{USAGE}");
+        }
+    }
+}
+
+async fn ghwatch_handler(msg: Message, bot: Bot, data: AppData) -> anyhow::Result<()> {
+    use modules::github::{self, RepoWatch};
+
+    const USAGE: &str =
+        "Usage: /ghwatch owner/repo [releases|tags|commits], /ghwatch del owner/repo [kind]";
+    let text = msg.text().expect("Unreachable");
+    let args: Vec<&str> = text.split_whitespace().skip(1).collect();
+    let chat_id = msg.chat.id.0;
+
+    if args.is_empty() {
+        let watches = github::list_watches(&data, chat_id)?;
+        if watches.is_empty() {
+            abort!(bot, msg, "No repository watched in this chat. {USAGE}");
+        }
+        let list = watches.iter().fold(String::new(), |mut acc, watch| {
+            writeln!(&mut acc, "* {} ({})", watch.repo, watch.kind).unwrap();
+            acc
+        });
+        abort!(bot, msg, "{list}");
+    }
+
+    let Some(requester) = msg.from.as_ref() else {
+        abort!(bot, msg, "Can't identify who are you");
+    };
+    if !is_chat_admin(&msg.chat, requester, bot.clone()).await {
+        abort!(
+            bot,
+            msg,
+            "Only the chat administrators can manage the watched repositories"
+        );
+    }
+
+    let (unwatch, args) = match args.split_first() {
+        Some((&"del", rest)) => (true, rest),
+        _ => (false, args.as_slice()),
+    };
+    let watch = match args {
+        [repo] => RepoWatch::new(repo, None),
+        [repo, kind] => RepoWatch::new(repo, Some(kind)),
+        _ => {
+            abort!(bot, msg, "{USAGE}");
+        }
+    };
+    let watch = match watch {
+        Ok(watch) => watch,
+        Err(err) => {
+            abort!(bot, msg, "{err}. {USAGE}");
+        }
+    };
+
+    if unwatch {
+        if github::unwatch_repo(&data, chat_id, &watch)? {
+            abort!(
+                bot,
+                msg,
+                "Stopped watching {} of {}",
+                watch.kind,
+                watch.repo
+            );
+        }
+        abort!(
+            bot,
+            msg,
+            "{} of {} is not watched in this chat",
+            watch.kind,
+            watch.repo
+        );
+    }
+
+    send_action!(@Typing; msg, bot);
+    match github::watch_repo(&data, chat_id, &watch).await {
+        Ok(()) => {
+            abort!(bot, msg, "Watching {} of {}", watch.kind, watch.repo);
+        }
+        Err(err) => {
+            abort!(bot, msg, "Fail to watch {}: {err}", watch.repo);
+        }
+    }
+}
+
+async fn stats_handler(msg: Message, bot: Bot, data: AppData) -> anyhow::Result<()> {
+    use modules::stats::{self, ChatStats};
+
+    const USAGE: &str = "Usage: /stats [7d|30d], /stats [on|off]";
+    let text = msg.text().expect("Unreachable");
+    let chat_id = msg.chat.id.0;
+    let opt_out = match text.split_whitespace().nth(1) {
+        Some("on") => false,
+        Some("off") => true,
+        period => {
+            let Some(days) = stats::parse_period(period.unwrap_or("7d")) else {
+                abort!(bot, msg, "{USAGE}");
+            };
+            if stats::is_opted_out(&data, chat_id)? {
+                abort!(
+                    bot,
+                    msg,
+                    "Command usage is not recorded in this chat, use /stats on to enable it"
+                );
+            }
+            let stats = ChatStats::collect(&data, chat_id, days)?;
+            abort!(bot, msg, "{}", stats.to_message());
+        }
+    };
+
+    let Some(requester) = msg.from.as_ref() else {
+        abort!(bot, msg, "Can't identify who are you");
+    };
+    if !is_chat_admin(&msg.chat, requester, bot.clone()).await {
+        abort!(
+            bot,
+            msg,
+            "Only the chat administrators can change this setting"
+        );
+    }
+    stats::set_opt_out(&data, chat_id, opt_out)?;
+    if opt_out {
+        abort!(
+            bot,
+            msg,
+            "Command usage is no longer recorded in this chat, the recorded stats are deleted"
+        );
+    }
+    abort!(bot, msg, "Command usage is recorded in this chat");
+}
+
+/// Abort the handler unless the message is sent by one of the bot admins in the config file
+macro_rules! require_bot_admin {
+    ($bot:expr, $msg:expr) => {
+        let is_admin = $msg
+            .from
+            .as_ref()
+            .is_some_and(|user| Config::get_global_config().admins.contains(&user.id.0));
+        if !is_admin {
+            abort!($bot, $msg, "Only the bot admins can use this command");
+        }
+    };
+}
+
+async fn broadcast_handler(msg: Message, bot: Bot, data: AppData) -> anyhow::Result<()> {
+    require_bot_admin!(bot, msg);
+    let text = msg.text().expect("Unreachable");
+    let Some((_, content)) = text.split_once(char::is_whitespace) else {
+        abort!(bot, msg, "Usage: /broadcast <text>");
+    };
+    let content = content.trim();
+    if content.is_empty() {
+        abort!(bot, msg, "Usage: /broadcast <text>");
+    }
+
+    let chats = modules::admin::subscribed_chats(&data)?;
+    let mut failed = Vec::new();
+    for chat_id in &chats {
+        if let Err(err) = bot.send_message(ChatId(*chat_id), content).await {
+            tracing::warn!("fail to broadcast to chat {chat_id}: {err}");
+            failed.push(chat_id.to_string());
+        }
+        // Stay under the Telegram limit of 30 messages per second
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+
+    if failed.is_empty() {
+        abort!(bot, msg, "Sent to {} chats", chats.len());
+    }
+    abort!(
+        bot,
+        msg,
+        "Sent to {} chats, failed in {} chats: {}",
+        chats.len() - failed.len(),
+        failed.len(),
+        failed.join(", ")
+    );
+}
+
+async fn reload_handler(msg: Message, bot: Bot, data: AppData) -> anyhow::Result<()> {
+    require_bot_admin!(bot, msg);
+    match modules::admin::reload_config(&data) {
+        Ok(report) => {
+            abort!(bot, msg, "{}", report.to_message());
+        }
+        Err(err) => {
+            abort!(bot, msg, "Config is not reloaded: {err:#}");
+        }
+    }
+}
+
+async fn chats_handler(msg: Message, bot: Bot, data: AppData) -> anyhow::Result<()> {
+    require_bot_admin!(bot, msg);
+    let chats = modules::admin::known_chats(&data)?;
+    abort!(bot, msg, "{}", modules::admin::format_known_chats(&chats));
+}
+
+async fn redis_handler(msg: Message, bot: Bot, data: AppData) -> anyhow::Result<()> {
+    require_bot_admin!(bot, msg);
+    let text = msg.text().expect("Unreachable");
+    if text.split_whitespace().nth(1) != Some("info") {
+        abort!(bot, msg, "Usage: /redis info");
+    }
+    let counts = modules::admin::key_prefix_counts(&data)?;
+    abort!(
+        bot,
+        msg,
+        "{}",
+        modules::admin::format_key_prefix_counts(&counts)
+    );
+}
+
+/// Reply the preview card of the bilibili video found in the message. Errors are only logged, as
+/// the user didn't ask for the preview explicitly.
+async fn send_bilibili_preview(msg: &Message, bot: &Bot, data: &AppData) {
+    let Some(text) = msg.text() else {
+        return;
+    };
+    let video = match modules::bilibili::preview_video_in_text(&data.bilibili_client(), text).await
+    {
+        Ok(Some(video)) => video,
+        Ok(None) => return,
+        Err(err) => {
+            tracing::warn!("[bilibili] fail to get video preview: {err}");
+            return;
+        }
+    };
+
+    let cover = match reqwest::Url::parse(&video.pic) {
+        Ok(cover) => cover,
+        Err(err) => {
+            tracing::warn!("[bilibili] invalid cover url {}: {err}", video.pic);
+            return;
+        }
+    };
+    let result = bot
+        .send_photo(msg.chat.id, InputFile::url(cover))
+        .caption(video.to_caption())
+        .parse_mode(ParseMode::Html)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await;
+    if let Err(err) = result {
+        tracing::warn!("[bilibili] fail to send video preview: {err}");
+    }
+}
+
+/// Download the video in the message if the chat enabled auto download. Users in cooldown are
+/// ignored silently to avoid spamming the chat. Return true if the download is started.
+async fn auto_download(
+    msg: &Message,
+    bot: Bot,
+    data: AppData,
+    url: reqwest::Url,
+) -> anyhow::Result<bool> {
+    let Some(user) = msg.from.as_ref() else {
+        return Ok(false);
+    };
+    let enabled: bool = data
+        .cacher
+        .get_conn()
+        .sismember(YTDLP_AUTO_DOWNLOAD_CHATS, msg.chat.id.0)?;
+    if !enabled {
+        return Ok(false);
+    }
+
+    let mode = DownloadMode::Video;
+    let is_cached = matches!(
+        get_cached_ytdlp_media(&data, &ytdlp_cache_key(&url, mode, None)),
+        Ok(Some(_))
+    );
+    if !is_cached && !try_start_ytdlp_cooldown(&data, user.id)? {
+        return Ok(false);
+    }
+
+    let request = YtdlpRequest {
+        chat_id: msg.chat.id,
+        url,
+        mode,
+        playlist_item: None,
+        reply_to: Some(msg.id),
+    };
+    let batch = data.download_queue.batch(user.id.0);
+    let tasks = data.tasks.clone();
+    tasks.spawn(async move {
+        if let Err(err) = run_ytdlp_job(bot, data, request, &batch).await {
+            tracing::error!("[ytdlp] fail to run auto download job: {err}");
+        }
+    });
+
+    Ok(true)
+}
+
 async fn callback_dispatcher(cb: CallbackQuery, bot: Bot, app_data: AppData) -> anyhow::Result<()> {
     bot.answer_callback_query(&cb.id).await?;
 
@@ -231,6 +729,7 @@ async fn callback_dispatcher(cb: CallbackQuery, bot: Bot, app_data: AppData) ->
     let payload = cb.data.as_deref().unwrap().split('.').collect::<Vec<_>>();
     match payload[0] {
         "make_quote" => add_photo_from_msg_to_sticker_set(cb, bot, app_data).await?,
+        "ytdlp_part" => download_playlist_item_from_cb(cb, bot, app_data).await?,
         _ => return Ok(()),
     }
 
@@ -465,7 +964,8 @@ async fn tr_handler(msg: Message, bot: Bot, data: AppData) -> Result<()> {
         target_lang = parse_lang!(args[1]);
     }
 
-    let current_usage = data.deepl.get_usage().await;
+    let deepl = data.deepl();
+    let current_usage = deepl.get_usage().await;
     if let Err(e) = current_usage {
         abort!(bot, msg, "fail to get current api usage: {}", e);
     }
@@ -479,12 +979,12 @@ async fn tr_handler(msg: Message, bot: Bot, data: AppData) -> Result<()> {
     }
 
     let result = if let Some(src) = source_lang {
-        data.deepl
+        deepl
             .translate_text(text, target_lang)
             .source_lang(src)
             .await
     } else {
-        data.deepl.translate_text(text, target_lang).await
+        deepl.translate_text(text, target_lang).await
     };
 
     if let Err(err) = result {
@@ -771,22 +1271,36 @@ async fn make_quote_handler(msg: Message, bot: Bot, data: AppData) -> Result<()>
     Ok(())
 }
 
-async fn get_chat_owner_from_cb(cb: &CallbackQuery, bot: Bot) -> Option<User> {
-    let msg = cb.message.as_ref()?;
-    match msg.chat().kind {
+async fn get_chat_owner(chat: &Chat, requester: &User, bot: Bot) -> Option<User> {
+    match chat.kind {
         ChatKind::Public(_) => {
             let member = bot
-                .get_chat_administrators(msg.chat().id)
+                .get_chat_administrators(chat.id)
                 .await
                 .ok()?
                 .into_iter()
                 .find(|member| member.is_owner())?;
             Some(member.user)
         }
-        ChatKind::Private(_) => Some(cb.from.clone()),
+        ChatKind::Private(_) => Some(requester.clone()),
     }
 }
 
+async fn is_chat_admin(chat: &Chat, requester: &User, bot: Bot) -> bool {
+    match chat.kind {
+        ChatKind::Public(_) => bot
+            .get_chat_member(chat.id, requester.id)
+            .await
+            .is_ok_and(|member| member.is_privileged()),
+        ChatKind::Private(_) => true,
+    }
+}
+
+async fn get_chat_owner_from_cb(cb: &CallbackQuery, bot: Bot) -> Option<User> {
+    let msg = cb.message.as_ref()?;
+    get_chat_owner(msg.chat(), &cb.from, bot).await
+}
+
 async fn download_photo(msg: &Message, bot: Bot) -> anyhow::Result<String> {
     let photos = msg
         .photo()
@@ -875,7 +1389,6 @@ async fn add_photo_from_msg_to_sticker_set(
         .await?;
 
     let result: anyhow::Result<()> = (async {
-        use teloxide::types::StickerFormat;
         // STEP1: Get photo file from telegram
         let dl_path = download_photo(msg, bot.clone()).await?;
 
@@ -970,6 +1483,151 @@ async fn add_photo_from_msg_to_sticker_set(
     Ok(())
 }
 
+async fn download_file_to_memory(bot: &Bot, file_id: &str) -> anyhow::Result<Vec<u8>> {
+    let file = bot.get_file(file_id).await?;
+    let mut buffer = std::io::Cursor::new(Vec::with_capacity(file.size as usize));
+    bot.download_file(&file.path, &mut buffer).await?;
+    Ok(buffer.into_inner())
+}
+
+/// Find the file that can be converted into a sticker from the given message, and the sticker
+/// format it should be converted into.
+fn find_sticker_source(msg: &Message) -> Option<(String, StickerFormat)> {
+    if let Some(photos) = msg.photo() {
+        return photos
+            .iter()
+            .max_by(|x, y| x.width.cmp(&y.width))
+            .map(|photo| (photo.file.id.to_string(), StickerFormat::Static));
+    }
+
+    // Telegram converts GIF into MPEG4 animation, so it must be handled before the document
+    if let Some(animation) = msg.animation() {
+        return Some((animation.file.id.to_string(), StickerFormat::Video));
+    }
+
+    if let Some(video) = msg.video() {
+        return Some((video.file.id.to_string(), StickerFormat::Video));
+    }
+
+    if let Some(document) = msg.document() {
+        let mime = document.mime_type.as_ref()?;
+        let format = match (mime.type_().as_str(), mime.subtype().as_str()) {
+            ("image", "gif") | ("video", _) => StickerFormat::Video,
+            ("image", _) => StickerFormat::Static,
+            _ => return None,
+        };
+        return Some((document.file.id.to_string(), format));
+    }
+
+    let sticker = msg.sticker()?;
+    if sticker.is_static() {
+        Some((sticker.file.id.to_string(), StickerFormat::Static))
+    } else if sticker.is_video() {
+        Some((sticker.file.id.to_string(), StickerFormat::Video))
+    } else {
+        // Animated sticker is a gzipped Lottie animation, which can't be handled by ffmpeg
+        None
+    }
+}
+
+async fn sticker_handler(msg: Message, bot: Bot) -> Result<()> {
+    const HELP: &str =
+        "Reply to a photo, an image file, a GIF, a short video or a sticker with /sticker [emoji]";
+
+    let Some(reply_to_msg) = msg.reply_to_message() else {
+        abort!(bot, msg, "{}", HELP);
+    };
+    let Some((file_id, format)) = find_sticker_source(reply_to_msg) else {
+        abort!(bot, msg, "Unsupported message type. {}", HELP);
+    };
+    let Some(requester) = msg.from.as_ref() else {
+        abort!(bot, msg, "Anonymous user can't create sticker");
+    };
+    let emoji = msg
+        .text()
+        .unwrap()
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or("🖼️")
+        .to_string();
+
+    send_action!(@Typing; msg, bot);
+
+    let Some(sticker_owner) = get_chat_owner(&msg.chat, requester, bot.clone()).await else {
+        abort!(
+            bot,
+            msg,
+            "Fail to find chat owner, sticker set need at least one owner"
+        );
+    };
+
+    let bot_info = bot.get_me().await?;
+    let (sticker_name, sticker_title) = match msg.chat.kind {
+        ChatKind::Public(_) => (
+            format!(
+                "chat_{}_by_{}",
+                msg.chat.id.0.unsigned_abs(),
+                bot_info.username()
+            ),
+            format!("Stickers of {}", msg.chat.title().unwrap_or("this chat")),
+        ),
+        ChatKind::Private(_) => (
+            format!("user_{}_by_{}", requester.id.0, bot_info.username()),
+            format!("Stickers of {}", requester.first_name),
+        ),
+    };
+
+    let result: anyhow::Result<()> = async {
+        let raw = download_file_to_memory(&bot, &file_id).await?;
+        let file = match format {
+            StickerFormat::Video => {
+                let webm = modules::sticker::legalize_video_sticker(&raw).await?;
+                InputFile::memory(webm).file_name("sticker.webm")
+            }
+            _ => {
+                let webp = tokio::task::block_in_place(|| {
+                    modules::sticker::legalize_static_sticker(&raw)
+                })?;
+                InputFile::memory(webp).file_name("sticker.webp")
+            }
+        };
+        let sticker = InputSticker {
+            sticker: file,
+            format,
+            emoji_list: vec![emoji],
+            mask_position: None,
+            keywords: Vec::new(),
+        };
+
+        add_or_create_sticker_set(
+            bot.clone(),
+            sticker,
+            sticker_owner.id,
+            &sticker_name,
+            &sticker_title,
+        )
+        .await
+    }
+    .await;
+
+    if let Err(err) = result {
+        abort!(bot, msg, "Fail to convert this image into sticker: {err}");
+    }
+
+    let sticker_set_link = rusty_maid::helper::Html::a(
+        &format!("https://t.me/addstickers/{}", sticker_name),
+        "sticker set",
+    );
+    bot.send_message(
+        msg.chat.id,
+        format!("Sticker added, see {}.", sticker_set_link),
+    )
+    .parse_mode(ParseMode::Html)
+    .await?;
+
+    Ok(())
+}
+
 async fn del_sticker_handler(msg: Message, bot: Bot) -> anyhow::Result<()> {
     let Some(target_sticker_msg) = msg.reply_to_message() else {
         abort!(bot, msg, "please reply to a sticker message");
@@ -989,8 +1647,227 @@ async fn del_sticker_handler(msg: Message, bot: Bot) -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn ytdlp_handler(msg: Message, bot: Bot, data: AppData) -> anyhow::Result<()> {
-    let user_id = msg.from.as_ref().unwrap().id;
+/// How long the uploaded file id is cached for the same URL
+const YTDLP_FILE_ID_CACHE_SECONDS: u64 = 60 * 60 * 24 * 30;
+
+/// Telegram file ids of an uploaded download, which can be sent again without downloading
+#[derive(Serialize, Deserialize)]
+struct CachedYtdlpMedia {
+    caption: String,
+    /// Kind and file id of each uploaded file
+    files: Vec<(MediaKind, String)>,
+}
+
+fn ytdlp_cache_key(url: &reqwest::Url, mode: DownloadMode, playlist_item: Option<usize>) -> String {
+    let format = match mode {
+        DownloadMode::Video => "video",
+        DownloadMode::Audio => "audio",
+    };
+    format!(
+        "YTDLP_FILE_ID:{format}:{}:{url}",
+        playlist_item.unwrap_or_default()
+    )
+}
+
+fn get_cached_ytdlp_media(data: &AppData, key: &str) -> anyhow::Result<Option<CachedYtdlpMedia>> {
+    let cached: Option<String> = data.cacher.get_conn().get(key)?;
+    let Some(cached) = cached else {
+        return Ok(None);
+    };
+    Ok(Some(serde_json::from_str(&cached)?))
+}
+
+fn set_cached_ytdlp_media(
+    data: &AppData,
+    key: &str,
+    media: &CachedYtdlpMedia,
+) -> anyhow::Result<()> {
+    let () = data.cacher.get_conn().set_ex(
+        key,
+        serde_json::to_string(media)?,
+        YTDLP_FILE_ID_CACHE_SECONDS,
+    )?;
+    Ok(())
+}
+
+/// Get the file id of the sent photo, video or audio
+fn sent_file_id(msg: &Message, kind: MediaKind) -> Option<String> {
+    let file_id = match kind {
+        MediaKind::Photo => &msg.photo()?.last()?.file.id,
+        MediaKind::Video => &msg.video()?.file.id,
+        MediaKind::Audio => &msg.audio()?.file.id,
+    };
+    Some(file_id.clone())
+}
+
+/// Split `total` items into balanced media groups. Telegram accepts 2 to 10 items in one media
+/// group, so 11 items are sent as 6 and 5 instead of 10 and 1.
+fn media_group_sizes(total: usize) -> Vec<usize> {
+    const MAX_GROUP_SIZE: usize = 10;
+    let groups = total.div_ceil(MAX_GROUP_SIZE);
+    (0..groups)
+        .map(|index| total / groups + usize::from(index < total % groups))
+        .collect()
+}
+
+/// Send the given files, either local paths or Telegram file ids, with the caption. Return the
+/// kind and file id of every sent file.
+async fn send_media_files(
+    bot: &Bot,
+    chat_id: ChatId,
+    reply_to: Option<MessageId>,
+    files: Vec<MediaFile>,
+    caption: &str,
+) -> anyhow::Result<Vec<(MediaKind, String)>> {
+    if files.len() <= 1 {
+        let Some(media) = files.into_iter().next() else {
+            anyhow::bail!("no file to upload");
+        };
+        let sent = send_media_file(bot, chat_id, reply_to, media, caption.to_string()).await?;
+        return Ok(sent.into_iter().collect());
+    }
+
+    let total = files.len();
+    let numbered = |index: usize| {
+        if index == 0 {
+            format!("[{}/{total}] {caption}", index + 1)
+        } else {
+            format!("[{}/{total}]", index + 1)
+        }
+    };
+    // Audio can only be grouped with audio, send them after the photos and videos
+    let (audios, visuals): (Vec<_>, Vec<_>) = files
+        .into_iter()
+        .enumerate()
+        .partition(|(_, media)| media.kind == MediaKind::Audio);
+
+    let mut sent = Vec::with_capacity(total);
+    for batch in [visuals, audios] {
+        if batch.len() == 1 {
+            let (index, media) = batch.into_iter().next().expect("batch has one file");
+            sent.extend(send_media_file(bot, chat_id, reply_to, media, numbered(index)).await?);
+            continue;
+        }
+
+        let kinds = batch
+            .iter()
+            .map(|(_, media)| media.kind)
+            .collect::<Vec<_>>();
+        let mut parts = batch.into_iter().map(|(index, media)| {
+            let caption = numbered(index);
+            match media.kind {
+                MediaKind::Photo => InputMedia::Photo(
+                    InputMediaPhoto::new(media.file)
+                        .caption(caption)
+                        .parse_mode(ParseMode::Html),
+                ),
+                MediaKind::Video => InputMedia::Video(
+                    InputMediaVideo::new(media.file)
+                        .caption(caption)
+                        .parse_mode(ParseMode::Html)
+                        .supports_streaming(true),
+                ),
+                MediaKind::Audio => InputMedia::Audio(
+                    InputMediaAudio::new(media.file)
+                        .caption(caption)
+                        .parse_mode(ParseMode::Html),
+                ),
+            }
+        });
+        let mut messages = Vec::with_capacity(kinds.len());
+        for size in media_group_sizes(kinds.len()) {
+            let group = parts.by_ref().take(size).collect::<Vec<_>>();
+            let mut request = bot.send_media_group(chat_id, group);
+            if let Some(reply_to) = reply_to {
+                request = request.reply_parameters(ReplyParameters::new(reply_to));
+            }
+            messages.extend(request.await?);
+        }
+        sent.extend(
+            messages
+                .iter()
+                .zip(kinds)
+                .filter_map(|(msg, kind)| Some((kind, sent_file_id(msg, kind)?))),
+        );
+    }
+    Ok(sent)
+}
+
+/// Send a single file with the caption, return its kind and file id
+async fn send_media_file(
+    bot: &Bot,
+    chat_id: ChatId,
+    reply_to: Option<MessageId>,
+    media: MediaFile,
+    caption: String,
+) -> anyhow::Result<Option<(MediaKind, String)>> {
+    let kind = media.kind;
+    let reply_parameters = reply_to.map(ReplyParameters::new);
+    let sent = match media.kind {
+        MediaKind::Photo => {
+            let mut request = bot
+                .send_photo(chat_id, media.file)
+                .caption(caption)
+                .parse_mode(ParseMode::Html);
+            request.reply_parameters = reply_parameters;
+            request.await?
+        }
+        MediaKind::Video => {
+            let mut request = bot
+                .send_video(chat_id, media.file)
+                .caption(caption)
+                .parse_mode(ParseMode::Html);
+            request.width = media.width;
+            request.height = media.height;
+            request.duration = media.duration;
+            request.thumbnail = media.thumbnail;
+            request.reply_parameters = reply_parameters;
+            request.await?
+        }
+        MediaKind::Audio => {
+            let mut request = bot
+                .send_audio(chat_id, media.file)
+                .caption(caption)
+                .parse_mode(ParseMode::Html);
+            request.title = media.title;
+            request.performer = media.performer;
+            request.duration = media.duration;
+            request.thumbnail = media.thumbnail;
+            request.reply_parameters = reply_parameters;
+            request.await?
+        }
+    };
+
+    Ok(sent_file_id(&sent, kind).map(|file_id| (kind, file_id)))
+}
+
+/// Upload the downloaded media, return the file ids that can be cached for the next request
+async fn upload_downloaded_media(
+    bot: &Bot,
+    chat_id: ChatId,
+    reply_to: Option<MessageId>,
+    media: &impl VideoDownloader,
+) -> anyhow::Result<CachedYtdlpMedia> {
+    let caption = media.provide_caption();
+    let files = send_media_files(bot, chat_id, reply_to, media.media_files(), &caption).await?;
+
+    Ok(CachedYtdlpMedia { caption, files })
+}
+
+const YTDLP_PROGRESS_EDIT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3);
+
+/// Max number of playlist entries that can be downloaded by one request
+const YTDLP_MAX_PLAYLIST_ITEMS: usize = 10;
+/// Max number of playlist entries listed in the selection keyboard
+const YTDLP_MAX_PLAYLIST_BUTTONS: usize = 30;
+
+/// Return false if the user has requested a download in the configured cooldown time
+fn try_start_ytdlp_cooldown(data: &AppData, user_id: UserId) -> anyhow::Result<bool> {
+    let cooldown = Config::get_global_config().yt_dlp.user_cooldown;
+    if cooldown == 0 {
+        return Ok(true);
+    }
+
     let rate_limit_key = format!("YTDLP_DOWNLOAD:USER:{}", user_id);
     let mut redis_cli = data.cacher.get_conn();
     let unhandle: bool = redis::cmd("SET")
@@ -998,20 +1875,48 @@ async fn ytdlp_handler(msg: Message, bot: Bot, data: AppData) -> anyhow::Result<
         .arg(1) // val
         .arg("NX") // NX
         .arg("EX") // EX
-        .arg(60) // SECONDS
+        .arg(cooldown) // SECONDS
         .query(&mut redis_cli)?;
-    if !unhandle {
-        abort!(
-            bot,
-            msg,
-            "You have requested a download task in last 1 min, please wait."
-        );
-    }
+    Ok(unhandle)
+}
 
+fn release_ytdlp_cooldown(data: &AppData, user_id: UserId) -> anyhow::Result<()> {
+    let rate_limit_key = format!("YTDLP_DOWNLOAD:USER:{}", user_id);
+    let () = data.cacher.get_conn().del(rate_limit_key)?;
+    Ok(())
+}
+
+async fn ytdlp_handler(msg: Message, bot: Bot, data: AppData) -> anyhow::Result<()> {
+    let user_id = msg.from.as_ref().unwrap().id;
     let text = msg.text().expect("Unreachable");
-    let payload = text.split(' ').skip(1).collect::<String>();
+    let mut args = text.split_whitespace().skip(1).peekable();
+
+    if args.next_if_eq(&"cancel").is_some() {
+        let cancelled = data.download_queue.cancel_by_owner(user_id.0);
+        if cancelled == 0 {
+            abort!(bot, msg, "You don't have any download task to cancel");
+        }
+        abort!(bot, msg, "Cancelled {cancelled} download task(s)");
+    }
+
+    let mode = if args.next_if_eq(&"-a").is_some() {
+        DownloadMode::Audio
+    } else {
+        DownloadMode::Video
+    };
+    let mut playlist_items = None;
+    let mut payload = String::new();
+    for arg in args {
+        match modules::ytd::parse_playlist_items(arg, YTDLP_MAX_PLAYLIST_ITEMS) {
+            Ok(Some(items)) => playlist_items = Some(items),
+            Ok(None) => payload.push_str(arg),
+            Err(err) => {
+                abort!(bot, msg, "{err}");
+            }
+        }
+    }
     if payload.len() < 2 {
-        abort!(bot, msg, "No URL given");
+        abort!(bot, msg, "No URL given. Usage: /ytdlp [-a] <url> [p1-3]");
     }
 
     let Some(capture) = MATCH_URL.captures(&payload) else {
@@ -1024,10 +1929,6 @@ async fn ytdlp_handler(msg: Message, bot: Bot, data: AppData) -> anyhow::Result<
             "Can't find URL from your input (This might be an internal regexp error)"
         );
     };
-    let resp = bot
-        .send_message(msg.chat.id, "Try downloading video...")
-        .await?;
-
     let final_url = if let Ok(clean_url) = data.url_cleaner.clear(url.as_str()).await {
         clean_url
     } else {
@@ -1035,52 +1936,390 @@ async fn ytdlp_handler(msg: Message, bot: Bot, data: AppData) -> anyhow::Result<
             .expect("internal error: fail to parse url, check REGEXP valid or not")
     };
 
-    let result = modules::ytd::YtdlpVideo::dl_from_url(final_url.as_str()).await;
+    // Cached media is sent without downloading, so it bypass the playlist check and the cooldown
+    let is_cached = playlist_items.is_none()
+        && matches!(
+            get_cached_ytdlp_media(&data, &ytdlp_cache_key(&final_url, mode, None)),
+            Ok(Some(_))
+        );
+
+    if !is_cached && !try_start_ytdlp_cooldown(&data, user_id)? {
+        abort!(
+            bot,
+            msg,
+            "You have requested a download task recently, please wait."
+        );
+    }
+
+    // Run the download in background, or the other updates from this chat, including the
+    // `/ytdlp cancel` command, will be blocked until the download finish.
+    let batch = data.download_queue.batch(user_id.0);
+    let tasks = data.tasks.clone();
+    tasks.spawn(async move {
+        let playlist_items = match playlist_items {
+            _ if is_cached => vec![None],
+            Some(items) => items.into_iter().map(Some).collect::<Vec<_>>(),
+            None => match fetch_ytdlp_playlist(&batch, &final_url).await {
+                Ok(Some(playlist)) if playlist.is_multi_part() => {
+                    // Nothing is downloaded until the user choose a part from the keyboard
+                    if let Err(err) = release_ytdlp_cooldown(&data, user_id) {
+                        tracing::warn!("[ytdlp] fail to release the cooldown: {err}");
+                    }
+                    let sent = send_ytdlp_playlist_keyboard(
+                        &bot, &msg, &data, &playlist, &final_url, mode,
+                    )
+                    .await;
+                    if let Err(err) = sent {
+                        tracing::error!("[ytdlp] fail to send playlist keyboard: {err}");
+                    }
+                    return;
+                }
+                Ok(Some(_)) => vec![None],
+                Ok(None) => return,
+                Err(err) => {
+                    // Let the download report the error if the URL is really broken
+                    tracing::warn!("[ytdlp] fail to list playlist for {final_url}: {err}");
+                    vec![None]
+                }
+            },
+        };
+
+        for item in playlist_items {
+            if batch.cancellation().is_cancelled() {
+                break;
+            }
+            let request = YtdlpRequest {
+                chat_id: msg.chat.id,
+                url: final_url.clone(),
+                mode,
+                playlist_item: item,
+                reply_to: None,
+            };
+            let result = run_ytdlp_job(bot.clone(), data.clone(), request, &batch).await;
+            if let Err(err) = result {
+                tracing::error!("[ytdlp] fail to run download job: {err}");
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// List the playlist entries in a queued job, so that it respects the worker limit and can be
+/// cancelled. Return `None` if the batch is cancelled.
+async fn fetch_ytdlp_playlist(
+    batch: &DownloadBatch,
+    url: &reqwest::Url,
+) -> anyhow::Result<Option<YtdlpPlaylist>> {
+    let mut job = batch.enqueue();
+    if job.wait_for_turn().await.is_err() {
+        return Ok(None);
+    }
+    tokio::select! {
+        playlist = YtdlpPlaylist::fetch(url.as_str()) => playlist.map(Some),
+        _ = job.cancellation().cancelled() => Ok(None),
+    }
+}
+
+async fn send_ytdlp_playlist_keyboard(
+    bot: &Bot,
+    msg: &Message,
+    data: &AppData,
+    playlist: &YtdlpPlaylist,
+    url: &reqwest::Url,
+    mode: DownloadMode,
+) -> anyhow::Result<()> {
+    let buttons = playlist
+        .entries
+        .iter()
+        .take(YTDLP_MAX_PLAYLIST_BUTTONS)
+        .enumerate()
+        .map(|(index, entry)| {
+            let index = index + 1;
+            let title = entry
+                .title
+                .as_deref()
+                .unwrap_or("Untitled")
+                .chars()
+                .take(40)
+                .collect::<String>();
+            vec![InlineKeyboardButton::callback(
+                format!("P{index}: {title}"),
+                format!("ytdlp_part.{index}"),
+            )]
+        });
+    let keyboard = InlineKeyboardMarkup::new(buttons);
+
+    let mut text = format!(
+        "{} has {} parts, choose the one you want to download.",
+        playlist.title.as_deref().unwrap_or("This playlist"),
+        playlist.entries.len()
+    );
+    if playlist.entries.len() > YTDLP_MAX_PLAYLIST_BUTTONS {
+        write!(
+            &mut text,
+            "\nOnly the first {YTDLP_MAX_PLAYLIST_BUTTONS} parts are listed, \
+            use /ytdlp <url> p{}-{} to select the rest.",
+            YTDLP_MAX_PLAYLIST_BUTTONS + 1,
+            playlist.entries.len()
+        )?;
+    }
+    let resp = bot
+        .send_message(msg.chat.id, text)
+        .reply_markup(keyboard)
+        .await?;
+
+    let key = format!("YTDLP_PLAYLIST:{}:{}", msg.chat.id, resp.id);
+    let mode = match mode {
+        DownloadMode::Video => "video",
+        DownloadMode::Audio => "audio",
+    };
+    let mut redis_cli = data.cacher.get_conn();
+    let () = redis_cli.hset_multiple(&key, &[("url", url.as_str()), ("mode", mode)])?;
+    let () = redis_cli.expire(&key, 60 * 60 * 24)?;
+
+    Ok(())
+}
+
+async fn download_playlist_item_from_cb(
+    cb: CallbackQuery,
+    bot: Bot,
+    data: AppData,
+) -> anyhow::Result<()> {
+    // Bound check is done by callback_dispatcher
+    let msg = cb.regular_message().unwrap();
+    let Some(item) = cb
+        .data
+        .as_deref()
+        .and_then(|payload| payload.split('.').nth(1))
+        .and_then(|item| item.parse::<usize>().ok())
+    else {
+        abort!(bot, msg, "Internal error: invalid playlist item");
+    };
+
+    let key = format!("YTDLP_PLAYLIST:{}:{}", msg.chat.id, msg.id);
+    let (url, mode): (Option<String>, Option<String>) =
+        data.cacher.get_conn().hget(&key, &["url", "mode"])?;
+    let Some(url) = url.and_then(|url| reqwest::Url::parse(&url).ok()) else {
+        bot.edit_message_text(msg.chat.id, msg.id, "This playlist selection has expired.")
+            .await?;
+        return Ok(());
+    };
+    let mode = match mode.as_deref() {
+        Some("audio") => DownloadMode::Audio,
+        _ => DownloadMode::Video,
+    };
+
+    if !try_start_ytdlp_cooldown(&data, cb.from.id)? {
+        abort!(
+            bot,
+            msg,
+            "{}, you have requested a download task recently, please wait.",
+            cb.from.first_name
+        );
+    }
+
+    let request = YtdlpRequest {
+        chat_id: msg.chat.id,
+        url,
+        mode,
+        playlist_item: Some(item),
+        reply_to: None,
+    };
+    let batch = data.download_queue.batch(cb.from.id.0);
+    let tasks = data.tasks.clone();
+    tasks.spawn(async move {
+        if let Err(err) = run_ytdlp_job(bot, data, request, &batch).await {
+            tracing::error!("[ytdlp] fail to run download job: {err}");
+        }
+    });
+
+    Ok(())
+}
+
+/// A download task requested by user
+struct YtdlpRequest {
+    chat_id: ChatId,
+    url: reqwest::Url,
+    mode: DownloadMode,
+    playlist_item: Option<usize>,
+    /// Send the status and the downloaded media as reply to this message
+    reply_to: Option<MessageId>,
+}
+
+async fn run_ytdlp_job(
+    bot: Bot,
+    data: AppData,
+    request: YtdlpRequest,
+    batch: &DownloadBatch,
+) -> anyhow::Result<()> {
+    let YtdlpRequest {
+        chat_id,
+        url,
+        mode,
+        playlist_item,
+        reply_to,
+    } = request;
+    let media_kind = match (mode, playlist_item) {
+        (DownloadMode::Video, None) => "video".to_string(),
+        (DownloadMode::Audio, None) => "audio".to_string(),
+        (DownloadMode::Video, Some(item)) => format!("video P{item}"),
+        (DownloadMode::Audio, Some(item)) => format!("audio P{item}"),
+    };
+    let cache_key = ytdlp_cache_key(&url, mode, playlist_item);
+    match get_cached_ytdlp_media(&data, &cache_key) {
+        Ok(Some(cached)) => {
+            let files = cached
+                .files
+                .iter()
+                .map(|(kind, file_id)| MediaFile::new(InputFile::file_id(file_id), *kind))
+                .collect();
+            let sent = send_media_files(&bot, chat_id, reply_to, files, &cached.caption).await;
+            match sent {
+                Ok(_) => return Ok(()),
+                // The file id might be expired, download it again
+                Err(err) => tracing::warn!("[ytdlp] fail to send cached {cache_key}: {err}"),
+            }
+        }
+        Ok(None) => (),
+        Err(err) => tracing::warn!("[ytdlp] fail to read cached {cache_key}: {err}"),
+    }
+
+    let mut resp = bot.send_message(chat_id, format!("Try downloading {media_kind}..."));
+    if let Some(reply_to) = reply_to {
+        resp = resp.reply_parameters(ReplyParameters::new(reply_to));
+    }
+    let resp = resp.await?;
+
+    let mut job = batch.enqueue();
+    let queue_reporter = {
+        let bot = bot.clone();
+        let queue = data.download_queue.clone();
+        let job_id = job.id();
+        tokio::spawn(async move {
+            let mut reported = None;
+            loop {
+                // Don't bother the user if the job can be started immediately
+                tokio::time::sleep(YTDLP_PROGRESS_EDIT_INTERVAL).await;
+                let Some(position) = queue.position(job_id) else {
+                    break;
+                };
+                if reported == Some(position) {
+                    continue;
+                }
+                let text = format!(
+                    "Waiting in the download queue, position: {position}\n\
+                    Use /ytdlp cancel to cancel the task"
+                );
+                if let Err(err) = bot.edit_message_text(chat_id, resp.id, text).await {
+                    tracing::warn!("fail to update download queue position: {err}");
+                }
+                reported = Some(position);
+            }
+        })
+    };
+    let waiting = job.wait_for_turn().await;
+    queue_reporter.abort();
+    if let Err(err) = waiting {
+        bot.edit_message_text(chat_id, resp.id, err.to_string())
+            .await?;
+        return Ok(());
+    }
+
+    let (progress, mut progress_rx) = tokio::sync::watch::channel(DownloadProgress::default());
+    let progress_reporter = {
+        let bot = bot.clone();
+        let media_kind = media_kind.clone();
+        tokio::spawn(async move {
+            while progress_rx.changed().await.is_ok() {
+                let text = format!(
+                    "Downloading {media_kind}...\n{}",
+                    *progress_rx.borrow_and_update()
+                );
+                if let Err(err) = bot.edit_message_text(chat_id, resp.id, text).await {
+                    tracing::warn!("fail to update download progress: {err}");
+                }
+                // Telegram limits how frequently a message can be edited, report progress lazily
+                tokio::time::sleep(YTDLP_PROGRESS_EDIT_INTERVAL).await;
+            }
+        })
+    };
+
+    let options = DownloadOptions {
+        mode,
+        playlist_item,
+        progress,
+    };
+    // Dropping the download future kills the yt-dlp process and removes its working directory
+    let result = tokio::select! {
+        result = RegistryMedia::download_from_url(url.as_str(), options) => result,
+        _ = job.cancellation().cancelled() => Err(anyhow::anyhow!("Download task cancelled")),
+    };
+    progress_reporter.abort();
 
     if let Err(err) = result {
         bot.edit_message_text(
-            msg.chat.id,
+            chat_id,
             resp.id,
-            format!("fail to download video: {err}"),
+            format!("fail to download {media_kind}: {err}"),
         )
         .await?;
         return Ok(());
     }
 
-    let video = result.unwrap();
-    let resp_text = if video.maybe_playlist {
-        "Uploading video...\n\
+    let mut video = result.unwrap();
+    let config = Config::get_global_config();
+    let fit_result = tokio::select! {
+        result = video.fit_upload_limit(config.upload_limit_mb(), config.yt_dlp.oversize) => result,
+        _ = job.cancellation().cancelled() => Err(anyhow::anyhow!("Download task cancelled")),
+    };
+    if let Err(err) = fit_result {
+        bot.edit_message_text(chat_id, resp.id, format!("fail to process video: {err}"))
+            .await?;
+        return Ok(());
+    }
+
+    let resp_text = if video.maybe_playlist() && playlist_item.is_none() {
+        format!(
+            "Uploading {media_kind}...\n\
             (This video appears to be in a playlist, but bot will only download p1. \
-             You will need to add another argument, such as '?p=3', to specify which video in the playlist \
-             you want to download.)"
+             You will need to add another argument, such as 'p3' or 'p1-3', to specify which video \
+             in the playlist you want to download.)"
+        )
     } else {
-        "Uploding video..."
+        format!("Uploading {media_kind}...")
     };
-    let resp = bot
-        .edit_message_text(msg.chat.id, resp.id, resp_text)
-        .await?;
-    let result = bot
-        .send_video(msg.chat.id, InputFile::file(&video.filename))
-        .caption(video.as_tg_video_caption())
-        .parse_mode(ParseMode::Html)
-        .width(video.width)
-        .height(video.height)
-        .thumbnail(InputFile::file(&video.thumbnail_filepath))
-        .await;
+    let resp = bot.edit_message_text(chat_id, resp.id, resp_text).await?;
+    let result = upload_downloaded_media(&bot, chat_id, reply_to, &video).await;
 
     let clean_result = video.clean().await;
     if let Err(err) = clean_result {
-        bot.edit_message_text(msg.chat.id, resp.id, format!("Fail to do clean up: {err}"))
+        bot.edit_message_text(chat_id, resp.id, format!("Fail to do clean up: {err}"))
             .await?;
         return Ok(());
     }
 
     // handle send result later to make sure video is indeed clear
-    if let Err(err) = result {
-        bot.edit_message_text(msg.chat.id, resp.id, format!("Fail to upload video: {err}"))
+    match result {
+        Ok(uploaded) => {
+            let cached = if uploaded.files.is_empty() {
+                Ok(())
+            } else {
+                set_cached_ytdlp_media(&data, &cache_key, &uploaded)
+            };
+            if let Err(err) = cached {
+                tracing::warn!("[ytdlp] fail to cache {cache_key}: {err}");
+            }
+            bot.delete_message(chat_id, resp.id).await?;
+        }
+        Err(err) => {
+            bot.edit_message_text(
+                chat_id,
+                resp.id,
+                format!("Fail to upload {media_kind}: {err}"),
+            )
             .await?;
-    } else {
-        bot.delete_message(msg.chat.id, resp.id).await?;
+        }
     }
 
     Ok(())
@@ -1137,3 +2376,12 @@ async fn jd_handler(msg: Message, bot: Bot) -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_media_group_sizes() {
+    assert_eq!(media_group_sizes(2), [2]);
+    assert_eq!(media_group_sizes(10), [10]);
+    assert_eq!(media_group_sizes(11), [6, 5]);
+    assert_eq!(media_group_sizes(21), [7, 7, 7]);
+    assert_eq!(media_group_sizes(23), [8, 8, 7]);
+}
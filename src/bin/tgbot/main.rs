@@ -1,14 +1,16 @@
 use anyhow::Context;
 use clearurl::UrlCleaner;
-use deepl::DeepLApi;
 use rusty_maid::{
-    app::{AppData, RuntimeData},
+    app::{AppData, ProxiedClients, RuntimeData},
     cache::Cacher,
     config::Config,
     http::HttpClient,
-    modules,
+    metrics,
+    modules::{self, download_queue::DownloadQueue},
 };
+use std::sync::Arc;
 use teloxide::{dispatching::dialogue, dptree, prelude::Dispatcher};
+use tokio_util::sync::CancellationToken;
 
 mod handlers;
 
@@ -35,44 +37,107 @@ async fn run() -> anyhow::Result<()> {
     } else {
         teloxide::Bot::new(&config.bot_token)
     };
+    let bot = if let Some(api_url) = &config.bot_api_url {
+        bot.set_api_url(reqwest::Url::parse(api_url).with_context(|| "invalid bot_api_url")?)
+    } else {
+        bot
+    };
 
     let handler = handlers::handler_schema();
     let dialogue_state = dialogue::InMemStorage::<handlers::DialogueStatus>::new();
     let app_data = prepare_app_data(&config).await;
 
-    modules::health::spawn_healthcheck_listner(config.health_check_port);
+    spawn_shutdown_listener(app_data.shutdown.clone());
+    modules::health::spawn_healthcheck_listner(
+        &config.health_check_host,
+        config.health_check_port,
+        app_data.clone(),
+    );
     modules::bilibili::spawn_bilibili_live_room_listener(bot.clone(), app_data.clone(), &config);
+    modules::bilibili::spawn_bilibili_dynamic_listener(bot.clone(), app_data.clone(), &config);
+    modules::twitch::spawn_twitch_live_listener(bot.clone(), app_data.clone(), &config);
+    modules::youtube::spawn_youtube_live_listener(bot.clone(), app_data.clone(), &config);
+    modules::rss::spawn_rss_listener(bot.clone(), app_data.clone());
+    modules::github::spawn_github_listener(bot.clone(), app_data.clone());
+    modules::admin::spawn_config_reloader(bot.clone(), app_data.clone());
 
-    Dispatcher::builder(bot, handler)
-        .dependencies(dptree::deps![app_data, dialogue_state])
-        .enable_ctrlc_handler()
+    let listener = teloxide::update_listeners::polling_default(bot.clone()).await;
+    let mut dispatcher = Dispatcher::builder(bot, handler)
+        .dependencies(dptree::deps![app_data.clone(), dialogue_state])
         .default_handler(|_| async move {})
-        .build()
-        .dispatch()
+        .build();
+    let dispatcher_shutdown = dispatcher.shutdown_token();
+    let shutdown = app_data.shutdown.clone();
+    tokio::spawn(async move {
+        shutdown.cancelled().await;
+        // The dispatcher waits for the running handlers before returning from `dispatch`. It can't
+        // be stopped before `dispatch` starts, so retry until it is running.
+        loop {
+            match dispatcher_shutdown.shutdown() {
+                Ok(stopped) => break stopped.await,
+                Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+            }
+        }
+    });
+    dispatcher
+        .dispatch_with_listener(
+            listener,
+            Arc::new(|err: teloxide::RequestError| async move {
+                metrics::record_polling_error();
+                tracing::error!("fail to poll updates from Telegram: {err}");
+            }),
+        )
         .await;
 
+    // Wait for the notifications being sent and the downloads being cleaned up
+    app_data.tasks.close();
+    let drain_timeout = Duration::from_secs(Config::get_global_config().shutdown_timeout);
+    if tokio::time::timeout(drain_timeout, app_data.tasks.wait())
+        .await
+        .is_err()
+    {
+        tracing::warn!(
+            "{} tasks are not finished in {drain_timeout:?}, exit anyway",
+            app_data.tasks.len()
+        );
+    }
+
     Ok(())
 }
 
+/// Cancel the shutdown token on Ctrl-C, or SIGTERM sent by `docker stop`
+fn spawn_shutdown_listener(shutdown: CancellationToken) {
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        let terminate = async {
+            use tokio::signal::unix::{signal, SignalKind};
+            match signal(SignalKind::terminate()) {
+                Ok(mut sigterm) => {
+                    sigterm.recv().await;
+                }
+                Err(err) => {
+                    tracing::error!("fail to listen SIGTERM: {err}");
+                    std::future::pending::<()>().await;
+                }
+            }
+        };
+        #[cfg(not(unix))]
+        let terminate = std::future::pending::<()>();
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => (),
+            _ = terminate => (),
+        }
+        tracing::info!("Shutting down...");
+        shutdown.cancel();
+    });
+}
+
 fn prepare_cache(cfg: &Config) -> Cacher {
     let client = redis::Client::open(cfg.redis_addr.as_str()).expect("fail to open client");
     Cacher::new(client)
 }
 
-pub fn prepare_deepl(cfg: &Config) -> DeepLApi {
-    use std::time::Duration;
-    let mut api_builder = DeepLApi::with(&cfg.deepl.api_key);
-    if let Some(proxy_url) = cfg.proxy.deepl() {
-        let client = reqwest::Client::builder()
-            .proxy(reqwest::Proxy::all(proxy_url).expect("proxy url not available"))
-            .timeout(Duration::from_secs(30))
-            .build()
-            .expect("request can not creat");
-        api_builder.client(client);
-    }
-    api_builder.new()
-}
-
 fn prepare_quote_maker() -> make_quote::QuoteProducer<'static> {
     let bold = include_bytes!(env!("QUOTE_TEXT_FONT_PATH"));
     let light = include_bytes!(env!("QUOTE_USERNAME_FONT_PATH"));
@@ -90,12 +155,15 @@ fn url_cleaner() -> UrlCleaner {
 }
 
 async fn prepare_app_data(cfg: &Config) -> AppData {
+    let shutdown = CancellationToken::new();
     let data = RuntimeData::builder()
         .cacher(prepare_cache(cfg))
         .requester(HttpClient::new())
-        .deepl(prepare_deepl(cfg))
+        .proxied(ProxiedClients::from_config(cfg).expect("fail to create proxied http clients"))
         .quote_maker(prepare_quote_maker())
         .url_cleaner(url_cleaner())
+        .download_queue(DownloadQueue::new(cfg.yt_dlp.workers, shutdown.clone()))
+        .shutdown(shutdown)
         .build();
 
     data.into()
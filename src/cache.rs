@@ -1,3 +1,4 @@
+use anyhow::Context;
 use redis::Commands;
 use std::{collections::HashSet, hash::Hash};
 
@@ -16,6 +17,13 @@ impl Cacher {
         self.0.get().expect("fail to get redis connection")
     }
 
+    /// Check that the Redis server is connectable, without panicking like [`Self::get_conn`]
+    pub fn ping(&self) -> anyhow::Result<()> {
+        let mut conn = self.0.get_timeout(std::time::Duration::from_secs(2))?;
+        let _: String = redis::cmd("PING").query(&mut *conn)?;
+        Ok(())
+    }
+
     pub fn setup_subscribe_registry<'iter, Subscriber, Event, Relation>(
         &self,
         event_name: &str,
@@ -25,17 +33,29 @@ impl Cacher {
         Event: Eq + Hash + std::fmt::Debug + std::fmt::Display + redis::ToRedisArgs + 'iter,
         Relation: Iterator<Item = (&'iter Subscriber, &'iter Vec<Event>)>,
     {
-        iter.for_each(|(k, v)| {
+        self.try_setup_subscribe_registry(event_name, iter)
+            .unwrap_or_else(|err| {
+                panic!("fail to initialize the {event_name} subscribe registry: {err:#}")
+            });
+    }
+
+    /// Same as [`Self::setup_subscribe_registry`] but return the error, used to apply the
+    /// relations from a reloaded config file
+    pub fn try_setup_subscribe_registry<'iter, Subscriber, Event, Relation>(
+        &self,
+        event_name: &str,
+        iter: Relation,
+    ) -> anyhow::Result<()>
+    where
+        Subscriber: Eq + Hash + std::fmt::Debug + redis::ToRedisArgs + 'iter,
+        Event: Eq + Hash + std::fmt::Debug + std::fmt::Display + redis::ToRedisArgs + 'iter,
+        Relation: Iterator<Item = (&'iter Subscriber, &'iter Vec<Event>)>,
+    {
+        for (k, v) in iter {
             self.subscribe_event(event_name, k, v)
-                .unwrap_or_else(|err| {
-                    panic!(
-                        "fail to initialize the {} subscribe registry \
-                        when subscribe event {:?} for registrant {:?}: \
-                        {err}",
-                        event_name, v, k
-                    )
-                });
-        });
+                .with_context(|| format!("fail to subscribe event {v:?} for registrant {k:?}"))?;
+        }
+        Ok(())
     }
 
     pub fn event_pool<Event>(&self, event_name: &str) -> anyhow::Result<Vec<Event>>
@@ -61,6 +81,46 @@ impl Cacher {
         Ok(subscriber)
     }
 
+    /// Subscribe a single event at runtime, besides the relations set up from the config file
+    pub fn subscribe<Subscriber, Event>(
+        &self,
+        event_name: &str,
+        subscriber: &Subscriber,
+        event: &Event,
+    ) -> anyhow::Result<()>
+    where
+        Subscriber: redis::ToRedisArgs,
+        Event: redis::ToRedisArgs + std::fmt::Display,
+    {
+        let mut conn = self.get_conn();
+        let key = format!("SUBSCRIBE_REGISTRY:{}:{}", event_name, event);
+        let () = conn.sadd(key, subscriber)?;
+        let () = conn.sadd(format!("REGISTRY_EVENT_POOL:{}", event_name), event)?;
+        Ok(())
+    }
+
+    /// Unsubscribe a single event, and remove it from the event pool when nobody subscribes it.
+    /// Return whether the event is still subscribed by others.
+    pub fn unsubscribe<Subscriber, Event>(
+        &self,
+        event_name: &str,
+        subscriber: &Subscriber,
+        event: &Event,
+    ) -> anyhow::Result<bool>
+    where
+        Subscriber: redis::ToRedisArgs,
+        Event: redis::ToRedisArgs + std::fmt::Display,
+    {
+        let mut conn = self.get_conn();
+        let key = format!("SUBSCRIBE_REGISTRY:{}:{}", event_name, event);
+        let () = conn.srem(&key, subscriber)?;
+        let remain: usize = conn.scard(&key)?;
+        if remain == 0 {
+            let () = conn.srem(format!("REGISTRY_EVENT_POOL:{}", event_name), event)?;
+        }
+        Ok(remain > 0)
+    }
+
     // Create `event = [registrant]` key-value pair
     fn subscribe_event<Subscriber, Event>(
         &self,
@@ -88,8 +148,14 @@ impl Cacher {
         }
 
         let garbage: Vec<String> = (&existing - &popingin).iter().cloned().collect();
-        for event in garbage {
-            let () = conn.srem(event, registrant)?;
+        let prefix = format!("SUBSCRIBE_REGISTRY:{event_name}:");
+        for key in garbage {
+            let () = conn.srem(&key, registrant)?;
+            // Nobody subscribes the event anymore, stop watching it like `Self::unsubscribe`
+            let remain: usize = conn.scard(&key)?;
+            if let (0, Some(event)) = (remain, key.strip_prefix(&prefix)) {
+                let () = conn.srem(&event_pool_key, event)?;
+            }
         }
 
         Ok(())
@@ -136,4 +202,15 @@ fn test_event_registry() {
     let subscribers: Vec<String> = cacher.get_subscribers(name, &3_i32).unwrap();
     assert_eq!(subscribers.len(), 1);
     assert!(subscribers.iter().any(|x| x == "baz"));
+
+    // "baz" unregisters everything, the events nobody subscribes are removed from the pool
+    let relation = std::collections::HashMap::from([
+        ("foo", vec![1, 2]),
+        ("bar", vec![1, 2]),
+        ("baz", vec![]),
+    ]);
+    cacher.setup_subscribe_registry(name, relation.iter());
+    let mut events: Vec<i32> = cacher.event_pool(name).unwrap();
+    events.sort();
+    assert_eq!(events, [1, 2]);
 }
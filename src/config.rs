@@ -1,10 +1,12 @@
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::sync::OnceLock;
+use std::sync::{Arc, RwLock};
 use std::{env, fs, path};
 
-static CONFIG: OnceLock<Config> = OnceLock::new();
+lazy_static::lazy_static!(
+    static ref CONFIG: RwLock<Arc<Config>> = RwLock::new(Arc::new(Config::from_path().unwrap()));
+);
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Config {
@@ -13,15 +15,50 @@ pub struct Config {
     pub redis_addr: String,
     #[serde(default = "log_level_default")]
     pub log_level: String,
+    /// Telegram user ids of the bot owners, who can use the bot management commands
+    #[serde(default)]
+    pub admins: Vec<u64>,
+    /// Address the health check and metrics server listening on
+    #[serde(default = "health_check_host_default")]
+    pub health_check_host: String,
     #[serde(default = "health_check_port_default")]
     pub health_check_port: u16,
+    /// Seconds to wait for the running tasks to finish when the bot is shutting down
+    #[serde(default = "shutdown_timeout_default")]
+    pub shutdown_timeout: u64,
+    /// URL of a self-hosted Telegram Bot API server, which raise the upload limit to 2000MB
+    pub bot_api_url: Option<String>,
 
     pub deepl: DeepLConfig,
 
     pub bili_live_room_event: HashMap<String, Vec<u64>>,
+    /// Chats subscribing to the new dynamic posts of the bilibili users
+    #[serde(default)]
+    pub bili_dynamic_event: HashMap<String, Vec<u64>>,
+    /// Chats subscribing to the new video uploads of the bilibili users
+    #[serde(default)]
+    pub bili_video_event: HashMap<String, Vec<u64>>,
+
+    /// Application credentials for the Twitch Helix API
+    pub twitch: Option<TwitchConfig>,
+    /// Chats subscribing to the live streams of the Twitch users, identified by login name
+    #[serde(default)]
+    pub twitch_live_event: HashMap<String, Vec<String>>,
+    /// Chats subscribing to the live streams of the YouTube channels, identified by channel id
+    #[serde(default)]
+    pub youtube_live_event: HashMap<String, Vec<String>>,
+
+    /// GitHub API credentials, which raise the rate limit of the repository watcher
+    pub github: Option<GithubConfig>,
 
     #[serde(default = "proxy_default")]
     pub proxy: ProxyConfig,
+
+    #[serde(default = "yt_dlp_default")]
+    pub yt_dlp: YtdlpConfig,
+
+    #[serde(default = "watcher_default")]
+    pub watcher: WatcherConfig,
 }
 
 impl Config {
@@ -44,14 +81,19 @@ impl Config {
         Ok(dir)
     }
 
+    /// Path of the config file, from `TG_MAID_CFG_PATH` or the config directory
+    pub fn file_path() -> anyhow::Result<path::PathBuf> {
+        if let Ok(cfg_path) = env::var("TG_MAID_CFG_PATH") {
+            return Ok(path::PathBuf::from(cfg_path));
+        }
+        let file_path = Self::get_config_dir()
+            .with_context(|| "fail to open config directory")?
+            .join("config.toml");
+        Ok(file_path)
+    }
+
     pub fn from_path() -> anyhow::Result<Self> {
-        let file_path = if let Ok(cfg_path) = env::var("TG_MAID_CFG_PATH") {
-            path::PathBuf::from(cfg_path)
-        } else {
-            Self::get_config_dir()
-                .with_context(|| "fail to open config directory")?
-                .join("config.toml")
-        };
+        let file_path = Self::file_path()?;
 
         if !file_path.exists() {
             anyhow::bail!("Config file not found in {file_path:?}");
@@ -60,14 +102,75 @@ impl Config {
 
         let config =
             toml::from_str::<Config>(&content).with_context(|| "fail to parse config from toml")?;
+        config.validate()?;
 
         Ok(config)
     }
 
-    pub fn get_global_config() -> &'static Config {
-        CONFIG.get_or_init(|| {
-            Self::from_path().unwrap()
-        })
+    /// Check the values that can't be checked by the deserializer
+    fn validate(&self) -> anyhow::Result<()> {
+        for (name, proxy_url) in self.proxy.urls() {
+            if let Some(proxy_url) = proxy_url {
+                reqwest::Proxy::all(proxy_url)
+                    .with_context(|| format!("invalid {name} proxy url {proxy_url:?}"))?;
+            }
+        }
+        if let Some(api_url) = &self.bot_api_url {
+            reqwest::Url::parse(api_url)
+                .with_context(|| format!("invalid bot_api_url {api_url:?}"))?;
+        }
+        anyhow::ensure!(
+            self.watcher.alert_threshold > 0,
+            "watcher.alert_threshold must be at least 1"
+        );
+        Ok(())
+    }
+
+    /// Settings changed in the new config that are only applied at startup
+    pub fn restart_required_changes(&self, new: &Config) -> Vec<&'static str> {
+        let changes = [
+            ("bot_token", self.bot_token != new.bot_token),
+            ("redis_addr", self.redis_addr != new.redis_addr),
+            ("bot_api_url", self.bot_api_url != new.bot_api_url),
+            (
+                "health_check_host",
+                self.health_check_host != new.health_check_host,
+            ),
+            (
+                "health_check_port",
+                self.health_check_port != new.health_check_port,
+            ),
+            (
+                "proxy.telegram",
+                self.proxy.telegram() != new.proxy.telegram(),
+            ),
+            ("yt_dlp.workers", self.yt_dlp.workers != new.yt_dlp.workers),
+        ];
+        changes
+            .into_iter()
+            .filter_map(|(name, changed)| changed.then_some(name))
+            .collect()
+    }
+
+    /// Max size in MB of the file that can be uploaded by the bot
+    pub fn upload_limit_mb(&self) -> u64 {
+        if self.bot_api_url.is_some() {
+            2000
+        } else {
+            50
+        }
+    }
+
+    /// The config currently in use, which is loaded from the file on the first call. Hold the
+    /// returned config only for a short time, so that a reloaded config is picked up.
+    pub fn get_global_config() -> Arc<Config> {
+        Arc::clone(&CONFIG.read().expect("config lock poisoned"))
+    }
+
+    /// Replace the config in use, return the previous one
+    pub fn set_global_config(config: Config) -> Arc<Config> {
+        let mut current = CONFIG.write().expect("config lock poisoned");
+        std::mem::replace(&mut *current, Arc::new(config))
     }
 }
 
@@ -76,6 +179,57 @@ pub struct DeepLConfig {
     pub api_key: String,
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TwitchConfig {
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GithubConfig {
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct YtdlpConfig {
+    /// Max number of yt-dlp processes running at the same time
+    #[serde(default = "yt_dlp_workers_default")]
+    pub workers: usize,
+    /// Seconds a user need to wait before requesting another download, 0 to disable the limit
+    #[serde(default = "yt_dlp_user_cooldown_default")]
+    pub user_cooldown: u64,
+    /// What to do when the video is larger than the upload limit
+    #[serde(default)]
+    pub oversize: OversizePolicy,
+    /// Caption templates keyed by the video domain, or `default` for all the other domains
+    #[serde(default)]
+    pub caption: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct WatcherConfig {
+    /// Chat notified when a watcher keeps failing and when it recovers
+    pub admin_chat: Option<i64>,
+    /// Number of consecutive failures before notifying the admin chat
+    #[serde(default = "watcher_alert_threshold_default")]
+    pub alert_threshold: u32,
+    /// Max seconds between two runs of a failing watcher
+    #[serde(default = "watcher_max_backoff_default")]
+    pub max_backoff: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OversizePolicy {
+    /// Refuse to download the video
+    #[default]
+    Fail,
+    /// Download the video and transcode it with lower bitrate and resolution
+    Transcode,
+    /// Download the video and split it into multiple parts
+    Split,
+}
+
 #[derive(Debug, Serialize)]
 pub enum ProxyType {
     UseDefault(bool),
@@ -104,6 +258,9 @@ pub struct ProxyConfig {
     deepl: Option<ProxyType>,
     bilibili: Option<ProxyType>,
     yt_dlp: Option<ProxyType>,
+    gallery_dl: Option<ProxyType>,
+    twitch: Option<ProxyType>,
+    youtube: Option<ProxyType>,
 }
 
 macro_rules! proxy_getter_generate {
@@ -130,19 +287,45 @@ macro_rules! proxy_getter_generate {
         }
     };
 }
+impl ProxyConfig {
+    /// Proxy url of every service, `None` if the service doesn't use a proxy
+    pub fn urls(&self) -> [(&'static str, Option<&str>); 7] {
+        [
+            ("telegram", self.telegram()),
+            ("deepl", self.deepl()),
+            ("bilibili", self.bilibili()),
+            ("yt_dlp", self.yt_dlp()),
+            ("gallery_dl", self.gallery_dl()),
+            ("twitch", self.twitch()),
+            ("youtube", self.youtube()),
+        ]
+    }
+}
+
 proxy_getter_generate!(telegram);
 proxy_getter_generate!(deepl);
 proxy_getter_generate!(bilibili);
 proxy_getter_generate!(yt_dlp);
+proxy_getter_generate!(gallery_dl);
+proxy_getter_generate!(twitch);
+proxy_getter_generate!(youtube);
 
 fn redis_addr_default() -> String {
     "redis://localhost:6379".to_string()
 }
 
+fn health_check_host_default() -> String {
+    "127.0.0.1".to_string()
+}
+
 fn health_check_port_default() -> u16 {
     11451
 }
 
+fn shutdown_timeout_default() -> u64 {
+    30
+}
+
 fn log_level_default() -> String {
     "INFO".to_string()
 }
@@ -154,6 +337,42 @@ fn proxy_default() -> ProxyConfig {
         deepl: None,
         bilibili: None,
         yt_dlp: None,
+        gallery_dl: None,
+        twitch: None,
+        youtube: None,
+    }
+}
+
+fn yt_dlp_workers_default() -> usize {
+    2
+}
+
+fn yt_dlp_user_cooldown_default() -> u64 {
+    60
+}
+
+fn yt_dlp_default() -> YtdlpConfig {
+    YtdlpConfig {
+        workers: yt_dlp_workers_default(),
+        user_cooldown: yt_dlp_user_cooldown_default(),
+        oversize: OversizePolicy::default(),
+        caption: HashMap::new(),
+    }
+}
+
+fn watcher_alert_threshold_default() -> u32 {
+    5
+}
+
+fn watcher_max_backoff_default() -> u64 {
+    3600
+}
+
+fn watcher_default() -> WatcherConfig {
+    WatcherConfig {
+        admin_chat: None,
+        alert_threshold: watcher_alert_threshold_default(),
+        max_backoff: watcher_max_backoff_default(),
     }
 }
 
@@ -182,3 +401,70 @@ fn validate_file_correctness() {
 
     fs::remove_dir(env::temp_dir().join("tg-maid-test-dir")).unwrap();
 }
+
+#[test]
+fn test_yt_dlp_config() {
+    let base = r#"
+        bot_token = "abcde"
+
+        [deepl]
+        api_key = "abcde"
+
+        [bili_live_room_event]
+    "#;
+    let config: Config = toml::from_str(base).unwrap();
+    assert_eq!(config.yt_dlp.workers, 2);
+    assert_eq!(config.yt_dlp.user_cooldown, 60);
+    assert_eq!(config.yt_dlp.oversize, OversizePolicy::Fail);
+    assert_eq!(config.upload_limit_mb(), 50);
+
+    let config: Config = toml::from_str(&format!(
+        "bot_api_url = \"http://127.0.0.1:8081\"\n{base}\n[yt_dlp]\nworkers = 4\noversize = \"split\"\n"
+    ))
+    .unwrap();
+    assert_eq!(config.yt_dlp.workers, 4);
+    assert_eq!(config.yt_dlp.user_cooldown, 60);
+    assert_eq!(config.yt_dlp.oversize, OversizePolicy::Split);
+    assert!(config.yt_dlp.caption.is_empty());
+    assert_eq!(config.upload_limit_mb(), 2000);
+
+    let config: Config = toml::from_str(&format!(
+        "{base}\n[yt_dlp.caption]\ndefault = \"{{title_link}}\"\n\"x.com\" = \"{{uploader}}\"\n"
+    ))
+    .unwrap();
+    assert_eq!(config.yt_dlp.caption["default"], "{title_link}");
+    assert_eq!(config.yt_dlp.caption["x.com"], "{uploader}");
+}
+
+#[test]
+fn test_reload_checks() {
+    let base = r#"
+        bot_token = "abcde"
+
+        [deepl]
+        api_key = "abcde"
+
+        [bili_live_room_event]
+    "#;
+    let config: Config = toml::from_str(base).unwrap();
+    assert!(config.validate().is_ok());
+
+    let new: Config = toml::from_str(&format!(
+        "health_check_port = 8080\n{base}\n[proxy]\ndefault = \"http://127.0.0.1:1080\"\n\
+        telegram = true\nbilibili = true\n"
+    ))
+    .unwrap();
+    assert!(new.validate().is_ok());
+    assert_eq!(
+        config.restart_required_changes(&new),
+        ["health_check_port", "proxy.telegram"]
+    );
+
+    let invalid: Config =
+        toml::from_str(&format!("{base}\n[proxy]\nbilibili = \"http://[::1\"\n")).unwrap();
+    assert!(invalid.validate().is_err());
+
+    let invalid: Config =
+        toml::from_str(&format!("{base}\n[watcher]\nalert_threshold = 0\n")).unwrap();
+    assert!(invalid.validate().is_err());
+}
@@ -1,17 +1,54 @@
 use std::{
+    collections::BTreeMap,
     fmt::Display,
     future::Future,
     hash::Hash,
     ops::{Deref, DerefMut},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     time::Duration,
 };
 
-use tokio::sync::watch;
+use rand::Rng;
+use teloxide::prelude::Requester;
 use typed_builder::TypedBuilder;
 
 use crate::app::AppData;
+use crate::config::Config;
 use crate::http::HttpClient;
+use crate::metrics;
+
+lazy_static::lazy_static!(
+    static ref WATCHER_STATS: Mutex<BTreeMap<String, Arc<WatcherStats>>> =
+        Mutex::new(BTreeMap::new());
+);
+
+/// Run results of a watcher since the bot started
+#[derive(Debug, Default)]
+pub struct WatcherStats {
+    pub successes: AtomicU64,
+    pub failures: AtomicU64,
+    pub consecutive_failures: AtomicU64,
+}
+
+/// Stats of every started watcher, keyed by the watcher name
+pub fn watcher_stats() -> BTreeMap<String, Arc<WatcherStats>> {
+    WATCHER_STATS
+        .lock()
+        .expect("watcher stats poisoned")
+        .clone()
+}
+
+/// Delay before the next run: the interval doubles on each consecutive failure up to `max_backoff`,
+/// and is randomly scaled by `jitter` (0.9 ~ 1.1) so that watchers don't hit the upstream together
+fn next_run_delay(interval: u64, failures: u32, max_backoff: u64, jitter: f64) -> Duration {
+    let backoff = interval
+        .saturating_mul(1_u64 << failures.min(16))
+        .min(max_backoff.max(interval));
+    Duration::from_secs_f64(backoff as f64 * jitter)
+}
 
 #[derive(Debug, Default, Clone, Copy)]
 pub struct State<S>(pub S);
@@ -44,9 +81,9 @@ pub struct EventWatcher<S> {
 
 impl<S> Clone for EventWatcher<S> {
     fn clone(&self) -> Self {
-        // bot & data is already wrapped by Arc
+        // bot, data & client is already wrapped by Arc
         Self {
-            client: None,
+            client: self.client.clone(),
             name: Arc::clone(&self.name),
             heartbeat_interval: self.heartbeat_interval,
             bot: self.bot.clone(),
@@ -68,36 +105,86 @@ where
         P: Promise,
         T: Fn(EventWatcher<S>) -> P + Sync + Send + 'static,
     {
-        let (tx, rx) = watch::channel(1_u8);
-        let mut heartbeat = tokio::time::interval(Duration::from_secs(self.heartbeat_interval));
         let name = self.name.to_string();
-
-        tokio::spawn(async move {
+        let stats = Arc::new(WatcherStats::default());
+        WATCHER_STATS
+            .lock()
+            .expect("watcher stats poisoned")
+            .insert(name.clone(), Arc::clone(&stats));
+
+        let shutdown = self.data.shutdown.clone();
+        let tasks = self.data.tasks.clone();
+        // Tracked so that the notification being sent is finished before the bot exits
+        tasks.spawn(async move {
+            let mut failures = 0_u32;
+            // Run the task immediately at start
+            let mut delay = Duration::ZERO;
             loop {
                 let watcher = self.clone();
-                let mut rx = rx.clone();
 
                 tokio::select! {
-                    _ = rx.changed() => {
+                    _ = shutdown.cancelled() => {
+                        tracing::info!("Quiting event watcher for {}...", name);
                         break;
                     }
-                    _ = heartbeat.tick() => {
-                        if let Err(err) = task(watcher).await {
-                            tracing::error!("{}", err)
+                    _ = tokio::time::sleep(delay) => {
+                        let result = task(watcher).await;
+                        // Read at every run to pick up the reloaded config
+                        let config = &Config::get_global_config().watcher;
+                        match result {
+                            Ok(()) => {
+                                stats.successes.fetch_add(1, Ordering::Relaxed);
+                                stats.consecutive_failures.store(0, Ordering::Relaxed);
+                                if failures > 0 && failures >= config.alert_threshold {
+                                    self.alert(format!(
+                                        "Watcher {} recovered after {failures} failures",
+                                        self.name
+                                    ))
+                                    .await;
+                                }
+                                failures = 0;
+                            }
+                            Err(err) => {
+                                stats.failures.fetch_add(1, Ordering::Relaxed);
+                                metrics::record_upstream_error(&err);
+                                stats.consecutive_failures.fetch_add(1, Ordering::Relaxed);
+                                failures = failures.saturating_add(1);
+                                tracing::error!("[{}] {} (failed {failures} times in a row)", self.name, err);
+                                if failures == config.alert_threshold {
+                                    self.alert(format!(
+                                        "Watcher {} failed {failures} times in a row: {err}",
+                                        self.name
+                                    ))
+                                    .await;
+                                }
+                            }
                         }
                     }
                 }
+
+                let jitter = rand::thread_rng().gen_range(0.9..=1.1);
+                delay = next_run_delay(
+                    self.heartbeat_interval,
+                    failures,
+                    Config::get_global_config().watcher.max_backoff,
+                    jitter,
+                );
             }
         });
+    }
 
-        let quit_on_ctrl_c = || async move {
-            tokio::signal::ctrl_c().await.ok();
-            tracing::info!("Quiting event watcher for {}...", name);
-            tx.send(0)
-                .unwrap_or_else(|_| panic!("fail to send signal into event watcher {}", name));
+    /// Notify the admin chat about the watcher health, errors are only logged
+    async fn alert(&self, text: String) {
+        let Some(admin_chat) = Config::get_global_config().watcher.admin_chat else {
+            return;
         };
-
-        tokio::spawn(quit_on_ctrl_c());
+        if let Err(err) = self
+            .bot
+            .send_message(teloxide::types::ChatId(admin_chat), text)
+            .await
+        {
+            tracing::error!("[{}] fail to alert admin chat: {err}", self.name);
+        }
     }
 
     pub fn setup_subscribe_registry<'iter, Subscriber, Event, Relation>(
@@ -134,3 +221,18 @@ where
         Ok(subscriber)
     }
 }
+
+#[test]
+fn test_next_run_delay() {
+    assert_eq!(next_run_delay(60, 0, 3600, 1.0), Duration::from_secs(60));
+    assert_eq!(next_run_delay(60, 1, 3600, 1.0), Duration::from_secs(120));
+    assert_eq!(next_run_delay(60, 3, 3600, 1.0), Duration::from_secs(480));
+    assert_eq!(next_run_delay(60, 10, 3600, 1.0), Duration::from_secs(3600));
+    assert_eq!(
+        next_run_delay(60, u32::MAX, 3600, 1.0),
+        Duration::from_secs(3600)
+    );
+    // The backoff never shortens the interval
+    assert_eq!(next_run_delay(600, 1, 300, 1.0), Duration::from_secs(600));
+    assert_eq!(next_run_delay(60, 0, 3600, 1.1), Duration::from_secs(66));
+}
@@ -43,7 +43,7 @@ macro_rules! generate_html_tags {
     };
 }
 
-generate_html_tags![code, b, i, u, s, span, pre];
+generate_html_tags![code, b, i, u, s, span, pre, blockquote];
 
 impl Html {
     #[inline]
@@ -51,3 +51,195 @@ impl Html {
         format!(r#"<a href="{href}">{text}</a>"#)
     }
 }
+
+impl Html {
+    /// Cut the HTML to at most `limit` visible characters, ending with `…` when anything is cut.
+    /// Tags are not counted and an entity counts as one character, like Telegram does for the
+    /// message length. The tags left open by the cut are closed.
+    pub fn truncate(html: &str, limit: usize) -> String {
+        let is_tag = |token: &str| token.len() > 1 && token.starts_with('<');
+        let visible = html_tokens(html).filter(|token| !is_tag(token)).count();
+        if visible <= limit {
+            return html.to_string();
+        }
+
+        let mut truncated = String::with_capacity(html.len());
+        let mut open_tags = Vec::new();
+        let mut remain = limit.saturating_sub(1);
+        for token in html_tokens(html) {
+            if is_tag(token) {
+                if token.starts_with("</") {
+                    open_tags.pop();
+                } else if !token.ends_with("/>") {
+                    let name = token[1..]
+                        .split(|c: char| c.is_whitespace() || c == '>')
+                        .next()
+                        .unwrap_or_default();
+                    open_tags.push(name);
+                }
+            } else if remain == 0 {
+                break;
+            } else {
+                remain -= 1;
+            }
+            truncated.push_str(token);
+        }
+        truncated.push('…');
+        for name in open_tags.into_iter().rev() {
+            truncated.push_str(&format!("</{name}>"));
+        }
+        truncated
+    }
+}
+
+/// Split the HTML into tags, entities and single characters
+fn html_tokens(html: &str) -> impl Iterator<Item = &str> {
+    let mut rest = html;
+    std::iter::from_fn(move || {
+        let first = rest.chars().next()?;
+        let len = match first {
+            '<' => rest.find('>').map(|end| end + 1),
+            '&' => rest.find(';').filter(|end| *end <= 10).map(|end| end + 1),
+            _ => None,
+        }
+        .unwrap_or(first.len_utf8());
+        let (token, remaining) = rest.split_at(len);
+        rest = remaining;
+        Some(token)
+    })
+}
+
+/// An unfinished Markdown element while rendering
+struct MarkdownFrame<'a> {
+    tag: Option<pulldown_cmark::Tag<'a>>,
+    text: String,
+    /// Number of the next item in an ordered list
+    next_number: Option<u64>,
+}
+
+impl Html {
+    /// Render Markdown into the HTML subset supported by Telegram. Tags that Telegram doesn't
+    /// support are flattened into text, and raw HTML is dropped.
+    pub fn from_markdown(markdown: &str) -> String {
+        use pulldown_cmark::{Event, Options, Parser, Tag};
+        use teloxide::utils::html::escape;
+
+        let options = Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS;
+        let mut stack = vec![MarkdownFrame {
+            tag: None,
+            text: String::new(),
+            next_number: None,
+        }];
+        let push_text = |stack: &mut Vec<MarkdownFrame>, text: &str| {
+            stack.last_mut().expect("root frame").text.push_str(text)
+        };
+
+        for event in Parser::new_ext(markdown, options) {
+            match event {
+                Event::Start(tag) => {
+                    let next_number = match tag {
+                        Tag::List(start) => start,
+                        _ => None,
+                    };
+                    stack.push(MarkdownFrame {
+                        tag: Some(tag),
+                        text: String::new(),
+                        next_number,
+                    });
+                }
+                Event::End(_) => {
+                    let Some(MarkdownFrame {
+                        tag: Some(tag),
+                        text,
+                        ..
+                    }) = stack.pop()
+                    else {
+                        continue;
+                    };
+                    let rendered = match tag {
+                        Tag::Paragraph => format!("{text}\n\n"),
+                        Tag::Heading { .. } => format!("{}\n", Html::b(text)),
+                        Tag::BlockQuote(_) => format!("{}\n", Html::blockquote(text.trim_end())),
+                        Tag::CodeBlock(_) => {
+                            format!("{}\n", Html::pre(text.trim_end_matches('\n')))
+                        }
+                        Tag::List(_) => format!("{text}\n"),
+                        Tag::Item => {
+                            let list = stack.last_mut().expect("item must be in a list");
+                            let bullet = match list.next_number.as_mut() {
+                                Some(number) => {
+                                    *number += 1;
+                                    format!("{}. ", *number - 1)
+                                }
+                                None => "• ".to_string(),
+                            };
+                            format!("{bullet}{}\n", text.trim_end())
+                        }
+                        Tag::Emphasis => Html::i(text),
+                        Tag::Strong => Html::b(text),
+                        Tag::Strikethrough => Html::s(text),
+                        Tag::Link { dest_url, .. } => Html::a(&escape(&dest_url), &text),
+                        Tag::Image { dest_url, .. } if text.is_empty() => {
+                            Html::a(&escape(&dest_url), "image")
+                        }
+                        Tag::Image { dest_url, .. } => Html::a(&escape(&dest_url), &text),
+                        _ => text,
+                    };
+                    push_text(&mut stack, &rendered);
+                }
+                Event::Text(text) => push_text(&mut stack, &escape(&text)),
+                Event::Code(code) => push_text(&mut stack, &Html::code(escape(&code))),
+                Event::SoftBreak | Event::HardBreak | Event::Rule => push_text(&mut stack, "\n"),
+                Event::TaskListMarker(checked) => {
+                    push_text(&mut stack, if checked { "☑ " } else { "☐ " })
+                }
+                _ => (),
+            }
+        }
+
+        let html = stack.swap_remove(0).text;
+        html.trim().to_string()
+    }
+}
+
+#[test]
+fn test_html_from_markdown() {
+    let markdown = r#"## What's Changed
+* Fix **crash** & `panic` by @alice in [#1](https://github.com/a/b/pull/1)
+* ~~Remove~~ *old* API
+
+1. first
+2. second
+
+<details>hidden</details>
+
+```rust
+fn main() {}
+```"#;
+    assert_eq!(
+        Html::from_markdown(markdown),
+        "<b>What's Changed</b>\n\
+        • Fix <b>crash</b> &amp; <code>panic</code> by @alice in \
+        <a href=\"https://github.com/a/b/pull/1\">#1</a>\n\
+        • <s>Remove</s> <i>old</i> API\n\
+        \n\
+        1. first\n\
+        2. second\n\
+        \n\
+        <pre>fn main() {}</pre>"
+    );
+    // Unclosed code block ends with the document
+    assert_eq!(Html::from_markdown("```\n<a>"), "<pre>&lt;a&gt;</pre>");
+}
+
+#[test]
+fn test_html_truncate() {
+    let html = "<b>标题 &amp; title</b> <a href=\"https://example.com\">link</a>";
+    assert_eq!(Html::truncate(html, 100), html);
+    assert_eq!(Html::truncate(html, 15), html);
+    assert_eq!(Html::truncate(html, 5), "<b>标题 &amp;…</b>");
+    assert_eq!(
+        Html::truncate(html, 14),
+        "<b>标题 &amp; title</b> <a href=\"https://example.com\">li…</a>"
+    );
+}
@@ -6,6 +6,7 @@ use std::fmt::Display;
 use std::ops::Deref;
 use std::time::Duration;
 
+#[derive(Clone)]
 pub struct HttpClient(
     #[cfg(feature = "reqwest")]
     pub reqwest::Client,
@@ -37,6 +38,16 @@ impl HttpClient {
         Self::default()
     }
 
+    /// Create a client that send all the requests through the given proxy
+    #[cfg(feature = "reqwest")]
+    pub fn with_proxy(proxy_url: &str) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .proxy(reqwest::Proxy::all(proxy_url).with_context(|| "proxy url not available")?)
+            .timeout(Duration::from_secs(30))
+            .build()?;
+        Ok(Self(client))
+    }
+
     #[cfg(feature = "reqwest")]
     #[inline]
     pub async fn to_t<T>(&self, url: impl reqwest::IntoUrl + std::fmt::Display) -> anyhow::Result<T>
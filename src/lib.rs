@@ -4,4 +4,5 @@ pub mod config;
 pub mod event;
 pub mod helper;
 pub mod http;
+pub mod metrics;
 pub mod modules;
@@ -0,0 +1,212 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::event::watcher_stats;
+
+/// Upper bounds in seconds of the handler latency histogram buckets
+const LATENCY_BUCKETS: [f64; 9] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+/// Telegram polling is unhealthy if it failed within this duration and no update came after that
+const POLLING_ERROR_WINDOW: Duration = Duration::from_secs(60);
+
+lazy_static::lazy_static!(
+    static ref METRICS: Mutex<Metrics> = Mutex::new(Metrics::default());
+);
+
+#[derive(Default)]
+struct Metrics {
+    commands: BTreeMap<String, CommandStats>,
+    /// Failed requests keyed by the upstream host
+    upstream_errors: BTreeMap<String, u64>,
+    last_update: Option<Instant>,
+    last_polling_error: Option<Instant>,
+}
+
+#[derive(Default)]
+struct CommandStats {
+    invocations: u64,
+    errors: u64,
+    /// Non-cumulative count of each bucket, the last one is `+Inf`
+    latency_buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    latency_sum: f64,
+}
+
+fn metrics() -> std::sync::MutexGuard<'static, Metrics> {
+    METRICS.lock().expect("metrics poisoned")
+}
+
+/// Record a finished command handler
+pub fn record_command(name: &str, elapsed: Duration, success: bool) {
+    let mut metrics = metrics();
+    let stats = metrics.commands.entry(name.to_string()).or_default();
+    stats.invocations += 1;
+    if !success {
+        stats.errors += 1;
+    }
+    let seconds = elapsed.as_secs_f64();
+    let bucket = LATENCY_BUCKETS
+        .iter()
+        .position(|bound| seconds <= *bound)
+        .unwrap_or(LATENCY_BUCKETS.len());
+    stats.latency_buckets[bucket] += 1;
+    stats.latency_sum += seconds;
+}
+
+/// Record the error if it is caused by a failed HTTP request
+pub fn record_upstream_error(err: &anyhow::Error) {
+    let Some(err) = err
+        .chain()
+        .find_map(|err| err.downcast_ref::<reqwest::Error>())
+    else {
+        return;
+    };
+    let upstream = err
+        .url()
+        .and_then(|url| url.host_str())
+        .unwrap_or("unknown")
+        .to_string();
+    *metrics().upstream_errors.entry(upstream).or_default() += 1;
+}
+
+/// Record an update received from Telegram, which means the polling works
+pub fn record_telegram_update() {
+    metrics().last_update = Some(Instant::now());
+}
+
+/// Record a failed Telegram polling request
+pub fn record_polling_error() {
+    metrics().last_polling_error = Some(Instant::now());
+}
+
+/// Whether the last Telegram polling succeeded. Empty pollings are invisible to the bot, so the
+/// polling is considered broken only when it failed recently and no update came after that.
+pub fn telegram_polling_healthy() -> bool {
+    let metrics = metrics();
+    match (metrics.last_polling_error, metrics.last_update) {
+        (None, _) => true,
+        (Some(error), Some(update)) if update > error => true,
+        (Some(error), _) => error.elapsed() > POLLING_ERROR_WINDOW,
+    }
+}
+
+/// Render all the metrics in the Prometheus text exposition format
+pub fn render() -> String {
+    let mut out = String::new();
+    let metrics = metrics();
+
+    out.push_str("# HELP tgbot_command_invocations_total Number of handled commands.\n");
+    out.push_str("# TYPE tgbot_command_invocations_total counter\n");
+    for (name, stats) in &metrics.commands {
+        writeln!(
+            out,
+            "tgbot_command_invocations_total{{command=\"{name}\"}} {}",
+            stats.invocations
+        )
+        .unwrap();
+    }
+
+    out.push_str("# HELP tgbot_command_errors_total Number of commands whose handler failed.\n");
+    out.push_str("# TYPE tgbot_command_errors_total counter\n");
+    for (name, stats) in &metrics.commands {
+        writeln!(
+            out,
+            "tgbot_command_errors_total{{command=\"{name}\"}} {}",
+            stats.errors
+        )
+        .unwrap();
+    }
+
+    out.push_str("# HELP tgbot_command_duration_seconds Time spent in the command handler.\n");
+    out.push_str("# TYPE tgbot_command_duration_seconds histogram\n");
+    for (name, stats) in &metrics.commands {
+        let mut cumulative = 0;
+        let bounds = LATENCY_BUCKETS
+            .iter()
+            .map(|bound| bound.to_string())
+            .chain(["+Inf".to_string()]);
+        for (bound, count) in bounds.zip(stats.latency_buckets) {
+            cumulative += count;
+            writeln!(
+                out,
+                "tgbot_command_duration_seconds_bucket{{command=\"{name}\",le=\"{bound}\"}} {cumulative}"
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "tgbot_command_duration_seconds_sum{{command=\"{name}\"}} {}",
+            stats.latency_sum
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "tgbot_command_duration_seconds_count{{command=\"{name}\"}} {}",
+            stats.invocations
+        )
+        .unwrap();
+    }
+
+    out.push_str("# HELP tgbot_watcher_ticks_total Number of watcher runs by result.\n");
+    out.push_str("# TYPE tgbot_watcher_ticks_total counter\n");
+    let watchers = watcher_stats();
+    for (name, stats) in &watchers {
+        let successes = stats.successes.load(Ordering::Relaxed);
+        let failures = stats.failures.load(Ordering::Relaxed);
+        writeln!(
+            out,
+            "tgbot_watcher_ticks_total{{watcher=\"{name}\",result=\"success\"}} {successes}"
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "tgbot_watcher_ticks_total{{watcher=\"{name}\",result=\"failure\"}} {failures}"
+        )
+        .unwrap();
+    }
+
+    out.push_str("# HELP tgbot_watcher_consecutive_failures Failed runs since the last success.\n");
+    out.push_str("# TYPE tgbot_watcher_consecutive_failures gauge\n");
+    for (name, stats) in &watchers {
+        writeln!(
+            out,
+            "tgbot_watcher_consecutive_failures{{watcher=\"{name}\"}} {}",
+            stats.consecutive_failures.load(Ordering::Relaxed)
+        )
+        .unwrap();
+    }
+
+    out.push_str("# HELP tgbot_upstream_http_errors_total Number of failed upstream requests.\n");
+    out.push_str("# TYPE tgbot_upstream_http_errors_total counter\n");
+    for (upstream, count) in &metrics.upstream_errors {
+        writeln!(
+            out,
+            "tgbot_upstream_http_errors_total{{upstream=\"{upstream}\"}} {count}"
+        )
+        .unwrap();
+    }
+
+    out
+}
+
+#[test]
+fn test_render_command_metrics() {
+    record_command("metricstest", Duration::from_millis(70), true);
+    record_command("metricstest", Duration::from_secs(60), false);
+
+    let rendered = render();
+    assert!(rendered.contains("tgbot_command_invocations_total{command=\"metricstest\"} 2\n"));
+    assert!(rendered.contains("tgbot_command_errors_total{command=\"metricstest\"} 1\n"));
+    assert!(rendered.contains(
+        "tgbot_command_duration_seconds_bucket{command=\"metricstest\",le=\"0.05\"} 0\n"
+    ));
+    assert!(rendered
+        .contains("tgbot_command_duration_seconds_bucket{command=\"metricstest\",le=\"0.1\"} 1\n"));
+    assert!(rendered
+        .contains("tgbot_command_duration_seconds_bucket{command=\"metricstest\",le=\"30\"} 1\n"));
+    assert!(rendered.contains(
+        "tgbot_command_duration_seconds_bucket{command=\"metricstest\",le=\"+Inf\"} 2\n"
+    ));
+    assert!(rendered.contains("tgbot_command_duration_seconds_count{command=\"metricstest\"} 2\n"));
+}
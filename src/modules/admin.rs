@@ -0,0 +1,327 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use redis::Commands;
+use teloxide::{prelude::Requester, types::Chat};
+
+use crate::{
+    app::{AppData, ProxiedClients},
+    config::Config,
+};
+
+use super::{bilibili, twitch, youtube};
+
+/// Hash of the chats that have used the bot, mapping the chat id to the chat name
+const KNOWN_CHATS: &str = "KNOWN_CHATS";
+/// Max number of chats listed in `/chats`, to keep the message under the Telegram limit
+const MAX_LISTED_CHATS: usize = 100;
+/// Interval to check whether the config file is modified
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Reloads can be triggered by `/reload`, SIGHUP and the file watcher at the same time
+static RELOAD_LOCK: Mutex<()> = Mutex::new(());
+
+fn chat_name(chat: &Chat) -> String {
+    if let Some(title) = chat.title() {
+        return title.to_string();
+    }
+    let name = [chat.first_name(), chat.last_name()]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(" ");
+    match chat.username() {
+        Some(username) if name.is_empty() => format!("@{username}"),
+        Some(username) => format!("{name} (@{username})"),
+        None => name,
+    }
+}
+
+/// Remember the chat so it can be listed in `/chats`
+pub fn record_known_chat(data: &AppData, chat: &Chat) -> anyhow::Result<()> {
+    let () = data
+        .cacher
+        .get_conn()
+        .hset(KNOWN_CHATS, chat.id.0, chat_name(chat))?;
+    Ok(())
+}
+
+/// Chats subscribing to any event, from both the config file and the runtime subscriptions
+pub fn subscribed_chats(data: &AppData) -> anyhow::Result<BTreeSet<i64>> {
+    let mut conn = data.cacher.get_conn();
+    let keys: Vec<String> = conn.scan_match("SUBSCRIBE_REGISTRY:*")?.collect();
+    let mut chats = BTreeSet::new();
+    for key in keys {
+        let subscribers: Vec<String> = conn.smembers(key)?;
+        chats.extend(subscribers.iter().filter_map(|id| id.parse::<i64>().ok()));
+    }
+    Ok(chats)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct KnownChat {
+    pub id: i64,
+    pub name: Option<String>,
+    pub subscribed: bool,
+}
+
+/// Chats that have used the bot or subscribed any event
+pub fn known_chats(data: &AppData) -> anyhow::Result<Vec<KnownChat>> {
+    let names: BTreeMap<i64, String> = data.cacher.get_conn().hgetall(KNOWN_CHATS)?;
+    let subscribed = subscribed_chats(data)?;
+    let ids: BTreeSet<i64> = names.keys().chain(&subscribed).copied().collect();
+    let chats = ids
+        .into_iter()
+        .map(|id| KnownChat {
+            id,
+            name: names.get(&id).cloned(),
+            subscribed: subscribed.contains(&id),
+        })
+        .collect();
+    Ok(chats)
+}
+
+pub fn format_known_chats(chats: &[KnownChat]) -> String {
+    if chats.is_empty() {
+        return "No chat known yet".to_string();
+    }
+
+    let mut message = format!("{} chats known:\n", chats.len());
+    for chat in chats.iter().take(MAX_LISTED_CHATS) {
+        message.push_str(&format!(
+            "\n* {} ({}){}",
+            chat.name.as_deref().unwrap_or("unknown"),
+            chat.id,
+            if chat.subscribed { " [subscribed]" } else { "" }
+        ));
+    }
+    if chats.len() > MAX_LISTED_CHATS {
+        message.push_str(&format!(
+            "\n... and {} more",
+            chats.len() - MAX_LISTED_CHATS
+        ));
+    }
+    message
+}
+
+/// Number of the Redis keys grouped by the prefix before the first `:`
+pub fn key_prefix_counts(data: &AppData) -> anyhow::Result<BTreeMap<String, u64>> {
+    let mut conn = data.cacher.get_conn();
+    let keys: Vec<String> = conn.scan()?.collect();
+    Ok(count_key_prefixes(keys.iter().map(String::as_str)))
+}
+
+fn count_key_prefixes<'key>(keys: impl Iterator<Item = &'key str>) -> BTreeMap<String, u64> {
+    let mut counts = BTreeMap::new();
+    for key in keys {
+        let prefix = key.split(':').next().unwrap_or(key);
+        *counts.entry(prefix.to_string()).or_default() += 1;
+    }
+    counts
+}
+
+pub fn format_key_prefix_counts(counts: &BTreeMap<String, u64>) -> String {
+    let total: u64 = counts.values().sum();
+    let mut message = format!("{total} keys in Redis\n");
+    for (prefix, count) in counts {
+        message.push_str(&format!("\n{prefix}: {count}"));
+    }
+    message
+}
+
+/// Apply the subscriptions in the new config file to the subscribe registries. The running
+/// watchers read their events from the registries, so the changes take effect in their next run.
+pub fn sync_subscriptions(data: &AppData, old: &Config, new: &Config) -> anyhow::Result<()> {
+    let cacher = &data.cacher;
+    cacher.try_setup_subscribe_registry(
+        bilibili::LIVE_ROOM_WATCHER,
+        with_removed(&old.bili_live_room_event, &new.bili_live_room_event).iter(),
+    )?;
+    cacher.try_setup_subscribe_registry(
+        bilibili::VIDEO_WATCHER,
+        with_removed(&old.bili_video_event, &new.bili_video_event).iter(),
+    )?;
+    cacher.try_setup_subscribe_registry(
+        bilibili::DYNAMIC_WATCHER,
+        with_removed(&old.bili_dynamic_event, &new.bili_dynamic_event).iter(),
+    )?;
+    cacher.try_setup_subscribe_registry(
+        twitch::TWITCH_WATCHER,
+        with_removed(
+            &twitch::subscribe_relation(&old.twitch_live_event),
+            &twitch::subscribe_relation(&new.twitch_live_event),
+        )
+        .iter(),
+    )?;
+    cacher.try_setup_subscribe_registry(
+        youtube::YOUTUBE_WATCHER,
+        with_removed(&old.youtube_live_event, &new.youtube_live_event).iter(),
+    )?;
+    Ok(())
+}
+
+/// The new relation, with the chats removed from the config file subscribing nothing, so that
+/// they are removed from the registry
+fn with_removed<Subscriber, Event>(
+    old: &HashMap<Subscriber, Vec<Event>>,
+    new: &HashMap<Subscriber, Vec<Event>>,
+) -> HashMap<Subscriber, Vec<Event>>
+where
+    Subscriber: Eq + Hash + Clone,
+    Event: Clone,
+{
+    let mut relation = new.clone();
+    for subscriber in old.keys() {
+        relation.entry(subscriber.clone()).or_default();
+    }
+    relation
+}
+
+#[derive(Debug)]
+pub struct ReloadReport {
+    /// Changed settings that are only applied at startup
+    pub restart_required: Vec<&'static str>,
+}
+
+impl ReloadReport {
+    pub fn to_message(&self) -> String {
+        if self.restart_required.is_empty() {
+            return "Config reloaded".to_string();
+        }
+        format!(
+            "Config reloaded, restart the bot to apply the changes of {}",
+            self.restart_required.join(", ")
+        )
+    }
+}
+
+/// Read the config file again, and replace the config in use, the subscriptions and the HTTP
+/// clients with the proxies. Nothing is changed if the new config is invalid.
+pub fn reload_config(data: &AppData) -> anyhow::Result<ReloadReport> {
+    let _guard = RELOAD_LOCK.lock().expect("reload lock poisoned");
+    let config = Config::from_path()?;
+    let clients = ProxiedClients::from_config(&config)?;
+
+    let current = Config::get_global_config();
+    sync_subscriptions(data, &current, &config)?;
+    data.set_proxied_clients(clients);
+    let report = ReloadReport {
+        restart_required: current.restart_required_changes(&config),
+    };
+    Config::set_global_config(config);
+    Ok(report)
+}
+
+/// Spawn a task reloading the config when the config file is modified or SIGHUP is received.
+/// The bot admins are notified when the new config fails to load.
+pub fn spawn_config_reloader(bot: teloxide::Bot, data: AppData) {
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        let mut sighup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        {
+            Ok(sighup) => Some(sighup),
+            Err(err) => {
+                tracing::error!("fail to listen SIGHUP: {err}");
+                None
+            }
+        };
+        let mut last_modified = config_modified_time();
+
+        loop {
+            #[cfg(unix)]
+            let hangup = async {
+                match sighup.as_mut() {
+                    Some(sighup) => sighup.recv().await,
+                    None => std::future::pending().await,
+                }
+            };
+            #[cfg(not(unix))]
+            let hangup = std::future::pending::<Option<()>>();
+
+            let trigger = tokio::select! {
+                _ = data.shutdown.cancelled() => break,
+                _ = hangup => "SIGHUP",
+                _ = tokio::time::sleep(CONFIG_POLL_INTERVAL) => {
+                    let modified = config_modified_time();
+                    if modified == last_modified {
+                        continue;
+                    }
+                    last_modified = modified;
+                    "file change"
+                }
+            };
+
+            match reload_config(&data) {
+                Ok(report) => tracing::info!("[{trigger}] {}", report.to_message()),
+                Err(err) => {
+                    let text = format!("Config is not reloaded on {trigger}: {err:#}");
+                    tracing::error!("{text}");
+                    for admin in &Config::get_global_config().admins {
+                        if let Err(err) = bot
+                            .send_message(teloxide::types::UserId(*admin), &text)
+                            .await
+                        {
+                            tracing::warn!("fail to notify admin {admin}: {err}");
+                        }
+                    }
+                }
+            }
+        }
+    });
+}
+
+fn config_modified_time() -> Option<SystemTime> {
+    let path = Config::file_path().ok()?;
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+#[test]
+fn test_admin_reports() {
+    let keys = [
+        "SUBSCRIBE_REGISTRY:RssFeedWatcher:https://example.com/feed",
+        "SUBSCRIBE_REGISTRY:TwitchLiveWatcher:foo",
+        "TG_AVATAR:123",
+        "KNOWN_CHATS",
+    ];
+    let counts = count_key_prefixes(keys.into_iter());
+    assert_eq!(
+        format_key_prefix_counts(&counts),
+        "4 keys in Redis\n\nKNOWN_CHATS: 1\nSUBSCRIBE_REGISTRY: 2\nTG_AVATAR: 1"
+    );
+
+    let chats = [
+        KnownChat {
+            id: -1001,
+            name: Some("Group".to_string()),
+            subscribed: true,
+        },
+        KnownChat {
+            id: 42,
+            name: None,
+            subscribed: false,
+        },
+    ];
+    assert_eq!(
+        format_known_chats(&chats),
+        "2 chats known:\n\n* Group (-1001) [subscribed]\n* unknown (42)"
+    );
+
+    let old = HashMap::from([("-1001", vec![1, 2]), ("-1002", vec![3])]);
+    let new = HashMap::from([("-1001", vec![2])]);
+    let relation = with_removed(&old, &new);
+    assert_eq!(relation.len(), 2);
+    assert_eq!(relation["-1001"], [2]);
+    assert!(relation["-1002"].is_empty());
+
+    let report = ReloadReport {
+        restart_required: vec!["bot_token", "redis_addr"],
+    };
+    assert_eq!(
+        report.to_message(),
+        "Config reloaded, restart the bot to apply the changes of bot_token, redis_addr"
+    );
+}
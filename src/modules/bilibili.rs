@@ -1,16 +1,38 @@
+use crate::helper::Html;
+use crate::http::HttpClient;
 use crate::{app::AppData, config::Config, event::EventWatcher};
-use redis::Commands;
+
+use super::live::{self, LivePlatform, LiveStream};
+use super::watch_list;
 use serde::Deserialize;
 use std::collections::HashMap;
-use teloxide::{payloads::SendPhotoSetters, prelude::Requester, types as tg_type};
-use crate::http::HttpClient;
+use teloxide::{
+    payloads::{SendMessageSetters, SendPhotoSetters},
+    prelude::Requester,
+    types as tg_type,
+};
 
 pub struct BiliApi;
 impl BiliApi {
     const BATCH_ROOM_INFO: &'static str =
         "https://api.live.bilibili.com/room/v1/Room/get_status_info_by_uids";
+    const VIDEO_VIEW: &'static str = "https://api.bilibili.com/x/web-interface/view";
+    const DYNAMIC_FEED: &'static str =
+        "https://api.bilibili.com/x/polymer/web-dynamic/v1/feed/space";
 }
 
+/// Bilibili and YouTube reject the requests without a browser user agent
+pub const BROWSER_USER_AGENT: &str =
+    "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/131.0 Safari/537.36";
+
+lazy_static::lazy_static!(
+    // `\b` doesn't split the id from the CJK text around it, check the ASCII boundary instead
+    static ref MATCH_BVID: regex::Regex =
+        regex::Regex::new(r"(?:^|[^0-9A-Za-z])(BV[0-9A-Za-z]{10})(?:[^0-9A-Za-z]|$)").unwrap();
+    static ref MATCH_SHORT_LINK: regex::Regex =
+        regex::Regex::new(r"https?://b23\.tv/[0-9A-Za-z]+").unwrap();
+);
+
 #[derive(Deserialize, Debug)]
 struct Response {
     code: u8,
@@ -18,25 +40,132 @@ struct Response {
     data: HashMap<String, RoomInfo>,
 }
 
-pub fn spawn_bilibili_live_room_listener(bot: teloxide::Bot, data: AppData, config: &Config) {
-    use crate::http::HttpClient;
-    use std::time::Duration;
-    let client = if let Some(proxu_url) = config.proxy.bilibili() {
-        let reqwest = reqwest::Client::builder()
-            .proxy(reqwest::Proxy::all(proxu_url).expect("proxy url not available"))
-            .timeout(Duration::from_secs(30))
-            .build()
-            .unwrap();
-        Some(HttpClient(reqwest))
+#[derive(Deserialize, Debug)]
+struct VideoViewResponse {
+    code: i64,
+    message: String,
+    data: Option<VideoInfo>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct VideoOwner {
+    pub mid: u64,
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct VideoStat {
+    pub view: u64,
+    pub like: u64,
+    pub danmaku: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct VideoInfo {
+    pub bvid: String,
+    pub title: String,
+    /// Cover image URL
+    pub pic: String,
+    /// Duration in seconds
+    pub duration: u64,
+    pub owner: VideoOwner,
+    pub stat: VideoStat,
+}
+
+impl VideoInfo {
+    pub fn to_caption(&self) -> String {
+        use teloxide::utils::html::escape;
+        let (minutes, seconds) = (self.duration / 60, self.duration % 60);
+        format!(
+            "{}\nUP：{}\n时长：{minutes}:{seconds:02} | 播放：{} | 点赞：{} | 弹幕：{}",
+            Html::a(
+                &format!("https://www.bilibili.com/video/{}", self.bvid),
+                &Html::b(escape(&self.title))
+            ),
+            Html::a(
+                &format!("https://space.bilibili.com/{}", self.owner.mid),
+                &escape(&self.owner.name)
+            ),
+            self.stat.view,
+            self.stat.like,
+            self.stat.danmaku,
+        )
+    }
+}
+
+/// Find the first BV id in the given text
+pub fn find_bvid(text: &str) -> Option<&str> {
+    MATCH_BVID
+        .captures(text)
+        .and_then(|caps| caps.get(1))
+        .map(|bvid| bvid.as_str())
+}
+
+/// Find the first b23.tv short link in the given text
+pub fn find_short_link(text: &str) -> Option<&str> {
+    MATCH_SHORT_LINK.find(text).map(|link| link.as_str())
+}
+
+/// Follow the redirection of the b23.tv short link and return the BV id of the target video
+pub async fn resolve_short_link(client: &HttpClient, link: &str) -> anyhow::Result<String> {
+    let response = client
+        .get(link)
+        .header(reqwest::header::USER_AGENT, BROWSER_USER_AGENT)
+        .send()
+        .await?;
+    let target = response.url().as_str();
+    let Some(bvid) = find_bvid(target) else {
+        anyhow::bail!("{link} doesn't redirect to a bilibili video");
+    };
+    Ok(bvid.to_string())
+}
+
+pub async fn get_video_info(client: &HttpClient, bvid: &str) -> anyhow::Result<VideoInfo> {
+    let response: VideoViewResponse = client
+        .get(BiliApi::VIDEO_VIEW)
+        .query(&[("bvid", bvid)])
+        .header(reqwest::header::USER_AGENT, BROWSER_USER_AGENT)
+        .send()
+        .await?
+        .json()
+        .await?;
+    if response.code != 0 {
+        anyhow::bail!("{}", response.message);
+    }
+    response
+        .data
+        .ok_or_else(|| anyhow::anyhow!("bilibili returns no video info for {bvid}"))
+}
+
+/// Find the bilibili video in the text and fetch its information for the preview card. Return
+/// `None` if there is no BV id or b23.tv short link in the text.
+pub async fn preview_video_in_text(
+    client: &HttpClient,
+    text: &str,
+) -> anyhow::Result<Option<VideoInfo>> {
+    let bvid = if let Some(bvid) = find_bvid(text) {
+        bvid.to_string()
+    } else if let Some(link) = find_short_link(text) {
+        resolve_short_link(client, link).await?
     } else {
-        None
+        return Ok(None);
     };
+    Ok(Some(get_video_info(client, &bvid).await?))
+}
+
+/// Name of the live room watcher, which is also the event name of the subscribe registry
+pub const LIVE_ROOM_WATCHER: &str = "BilibiliLiveRoomWatcher";
+/// Name of the video upload watcher
+pub const VIDEO_WATCHER: &str = "BilibiliVideoWatcher";
+/// Name of the dynamic post watcher
+pub const DYNAMIC_WATCHER: &str = "BilibiliDynamicWatcher";
 
+pub fn spawn_bilibili_live_room_listener(bot: teloxide::Bot, data: AppData, config: &Config) {
     EventWatcher::builder()
-        .name("BilibiliLiveRoomWatcher")
+        .name(LIVE_ROOM_WATCHER)
         .bot(bot)
         .data(data)
-        .client(client)
+        .client(None)
         .heartbeat_interval(120) // 2mins
         .build()
         .setup_subscribe_registry(config.bili_live_room_event.iter())
@@ -55,37 +184,37 @@ pub struct RoomInfo {
     uid: u64,
     online: u64,
     keyframe: String,
+    /// Unix timestamp when the stream started, 0 if not living
+    #[serde(default)]
+    live_time: i64,
 }
 
 impl RoomInfo {
-    fn to_captions(&self, status: u8) -> Option<String> {
-        match status {
-            0 => Some(format!(
-                "{} 下播了！",
-                self.username,
-            )),
-            1 => Some(format!(
-                "<a href=\"{}\">{}</a> 开播了！已有 {} 人正在观看\n直播: <a href=\"{}\">{}</a>\n分区: #{}\n",
-                format_args!("https://space.bilibili.com/{}/", self.uid),
-                self.username,
-                self.online,
-                format_args!("https://live.bilibili.com/{}/", self.room_id),
-                self.title,
-                self.area_v2_name
-            )),
-            _ => None,
+    fn to_live_stream(&self) -> LiveStream {
+        LiveStream {
+            platform: LivePlatform::Bilibili,
+            room_id: self.room_id.to_string(),
+            streamer: self.username.clone(),
+            streamer_url: format!("https://space.bilibili.com/{}/", self.uid),
+            title: self.title.clone(),
+            url: format!("https://live.bilibili.com/{}/", self.room_id),
+            // The live status 0 is stopped and 2 is the video rotation after the stream ends
+            living: self.live_status == 1,
+            online: Some(self.online),
+            area: Some(self.area_v2_name.clone()),
+            cover: self.cover_from_user.clone(),
+            keyframe: Some(self.keyframe.clone()),
+            start_time: Some(self.live_time),
         }
     }
 }
 
 pub async fn batch_get_room_info(
-    data: &AppData,
-    client: Option<&HttpClient>,
+    client: &HttpClient,
     user_ids: impl Iterator<Item = &u64>,
 ) -> anyhow::Result<HashMap<String, RoomInfo>> {
     let payload = HashMap::from([("uids", user_ids.collect::<Vec<_>>())]);
     let info = client
-        .unwrap_or(&data.requester)
         .post_json_to_t::<Response>(&payload, BiliApi::BATCH_ROOM_INFO)
         .await?;
 
@@ -96,42 +225,248 @@ pub async fn batch_get_room_info(
     Ok(info.data)
 }
 
-pub fn cache_bili_live_room_status(data: &AppData, info: &RoomInfo) -> anyhow::Result<u8> {
-    let key = format!("BILI_LIVE_ROOM_STATUS:{}", info.room_id);
-    let mut conn = data.cacher.get_conn();
-    let prev_status: Option<u8> = conn.get(&key)?;
+async fn watch_and_response(ctx: EventWatcher<()>) -> anyhow::Result<()> {
+    let subscribed_rooms = ctx.event_pool()?;
+    let response =
+        batch_get_room_info(&ctx.data.bilibili_client(), subscribed_rooms.iter()).await?;
 
-    let () = conn.set(&key, info.live_status)?;
+    let streams = response
+        .into_values()
+        .map(|room_info| (room_info.uid, room_info.to_live_stream()));
+    live::process_live_streams(&ctx, streams).await
+}
 
-    if info.live_status == 1 {
-        let key = format!("BILI_LIVE_ROOM_STATUS:{}:KEYFRAME", info.room_id);
-        let () = conn.set(&key, &info.keyframe)?;
-    }
+/// Kind of the bilibili feed items that a watcher notifies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DynamicSubscription {
+    /// New video uploads
+    Video,
+    /// All the other dynamic posts
+    Post,
+}
 
-    // 255 indicate that the status is not exist before
-    Ok(prev_status.unwrap_or(255))
+#[derive(Deserialize, Debug)]
+struct DynamicFeedResponse {
+    code: i64,
+    message: String,
+    data: Option<DynamicFeed>,
 }
 
-async fn watch_and_response(ctx: EventWatcher<()>) -> anyhow::Result<()> {
-    let subscribed_rooms = ctx.event_pool()?;
-    let response = batch_get_room_info(&ctx.data, ctx.client.as_ref(), subscribed_rooms.iter()).await?;
+#[derive(Deserialize, Debug)]
+struct DynamicFeed {
+    #[serde(default)]
+    items: Vec<DynamicItem>,
+}
 
-    for (_, room_info) in response {
-        let prev_status = cache_bili_live_room_status(&ctx.data, &room_info);
-        if let Err(err) = prev_status {
-            tracing::error!("[BiliLiveRoom] fail to update cache: {err}");
-            continue;
+#[derive(Deserialize, Debug)]
+pub struct DynamicItem {
+    pub id_str: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub modules: DynamicModules,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DynamicModules {
+    pub module_author: DynamicAuthor,
+    pub module_dynamic: Option<DynamicContent>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DynamicAuthor {
+    pub mid: u64,
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DynamicContent {
+    pub desc: Option<DynamicText>,
+    pub major: Option<DynamicMajor>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DynamicText {
+    pub text: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DynamicMajor {
+    pub archive: Option<DynamicArchive>,
+    pub draw: Option<DynamicDraw>,
+    pub opus: Option<DynamicOpus>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DynamicArchive {
+    pub bvid: String,
+    pub title: String,
+    pub cover: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DynamicDraw {
+    pub items: Vec<DynamicPicture>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DynamicPicture {
+    #[serde(alias = "url")]
+    pub src: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DynamicOpus {
+    pub summary: Option<DynamicText>,
+    #[serde(default)]
+    pub pics: Vec<DynamicPicture>,
+}
+
+impl DynamicItem {
+    const VIDEO_TYPE: &'static str = "DYNAMIC_TYPE_AV";
+
+    fn is_video(&self) -> bool {
+        self.kind == Self::VIDEO_TYPE
+    }
+
+    fn major(&self) -> Option<&DynamicMajor> {
+        self.modules.module_dynamic.as_ref()?.major.as_ref()
+    }
+
+    /// Text of the post, or the summary for the long article
+    fn text(&self) -> Option<&str> {
+        let content = self.modules.module_dynamic.as_ref()?;
+        content
+            .desc
+            .as_ref()
+            .or_else(|| content.major.as_ref()?.opus.as_ref()?.summary.as_ref())
+            .map(|desc| desc.text.as_str())
+    }
+
+    /// Video cover or the first picture of the post
+    fn cover(&self) -> Option<&str> {
+        let major = self.major()?;
+        if let Some(archive) = &major.archive {
+            return Some(&archive.cover);
         }
+        major
+            .draw
+            .as_ref()
+            .and_then(|draw| draw.items.first())
+            .or_else(|| major.opus.as_ref()?.pics.first())
+            .map(|pic| pic.src.as_str())
+    }
+
+    fn to_caption(&self) -> String {
+        use teloxide::utils::html::escape;
+        let author = &self.modules.module_author;
+        let author_link = Html::a(
+            &format!("https://space.bilibili.com/{}", author.mid),
+            &escape(&author.name),
+        );
+        let text = self
+            .text()
+            .map(|text| escape(&text.chars().take(500).collect::<String>()))
+            .unwrap_or_default();
+
+        match self.major().and_then(|major| major.archive.as_ref()) {
+            Some(archive) => format!(
+                "{author_link} 投稿了新视频\n{}\n{text}",
+                Html::a(
+                    &format!("https://www.bilibili.com/video/{}", archive.bvid),
+                    &Html::b(escape(&archive.title))
+                )
+            ),
+            None => format!(
+                "{author_link} 发布了新动态\n{text}\n{}",
+                Html::a(
+                    &format!("https://t.bilibili.com/{}", self.id_str),
+                    "查看动态"
+                )
+            ),
+        }
+    }
+}
 
-        let status_unchanged = prev_status.unwrap() == room_info.live_status;
-        if status_unchanged {
+pub async fn get_user_dynamics(client: &HttpClient, mid: u64) -> anyhow::Result<Vec<DynamicItem>> {
+    let response: DynamicFeedResponse = client
+        .get(BiliApi::DYNAMIC_FEED)
+        .query(&[("host_mid", mid)])
+        .header(reqwest::header::USER_AGENT, BROWSER_USER_AGENT)
+        .send()
+        .await?
+        .json()
+        .await?;
+    if response.code != 0 {
+        anyhow::bail!("{}", response.message);
+    }
+    Ok(response.data.map(|feed| feed.items).unwrap_or_default())
+}
+
+pub fn spawn_bilibili_dynamic_listener(bot: teloxide::Bot, data: AppData, config: &Config) {
+    let subscriptions = [
+        (
+            VIDEO_WATCHER,
+            DynamicSubscription::Video,
+            &config.bili_video_event,
+        ),
+        (
+            DYNAMIC_WATCHER,
+            DynamicSubscription::Post,
+            &config.bili_dynamic_event,
+        ),
+    ];
+    for (name, subscription, relation) in subscriptions {
+        EventWatcher::builder()
+            .name(name)
+            .bot(bot.clone())
+            .data(data.clone())
+            .client(None)
+            .heartbeat_interval(300) // 5mins
+            .state(subscription)
+            .build()
+            .setup_subscribe_registry(relation.iter())
+            .start_with_task(watch_dynamic_and_response);
+    }
+}
+
+async fn watch_dynamic_and_response(ctx: EventWatcher<DynamicSubscription>) -> anyhow::Result<()> {
+    let subscription = ctx
+        .state
+        .as_ref()
+        .expect("dynamic watcher must have subscription state")
+        .0;
+    let watcher = match subscription {
+        DynamicSubscription::Video => "VIDEO",
+        DynamicSubscription::Post => "POST",
+    };
+
+    let users: Vec<u64> = ctx.event_pool()?;
+    for mid in users {
+        let items = match get_user_dynamics(&ctx.data.bilibili_client(), mid).await {
+            Ok(items) => items,
+            Err(err) => {
+                tracing::error!("[BiliDynamic] fail to get dynamics of {mid}: {err}");
+                continue;
+            }
+        };
+        let items: Vec<_> = items
+            .into_iter()
+            .filter(|item| item.is_video() == (subscription == DynamicSubscription::Video))
+            .collect();
+
+        let key = format!("BILI_DYNAMIC_SEEN:{watcher}:{mid}");
+        let unseen = watch_list::filter_unseen(&ctx.data, &key, &items, |item| &item.id_str)?;
+        if unseen.is_empty() {
             continue;
         }
 
-        let subscribers = ctx.get_subscribers(&room_info.uid)?;
-        for chat_id in subscribers {
-            if let Err(err) = notify_live_room_changes(&ctx, chat_id, &room_info).await {
-                tracing::error!("[BiliLiveRoom] fail to notify changes: {err}")
+        let subscribers: Vec<i64> = ctx.get_subscribers(&mid)?;
+        // Feed is sorted from the newest, notify in the posting order
+        for item in unseen.into_iter().rev() {
+            for chat_id in &subscribers {
+                if let Err(err) = notify_dynamic(&ctx, *chat_id, item).await {
+                    tracing::error!("[BiliDynamic] fail to notify dynamic: {err}")
+                }
             }
         }
     }
@@ -139,30 +474,114 @@ async fn watch_and_response(ctx: EventWatcher<()>) -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn notify_live_room_changes(
-    ctx: &EventWatcher<()>,
+async fn notify_dynamic(
+    ctx: &EventWatcher<DynamicSubscription>,
     chat_id: i64,
-    room_info: &RoomInfo,
+    item: &DynamicItem,
 ) -> anyhow::Result<()> {
-    let cover = if room_info.live_status == 0 {
-        let mut conn = ctx.data.cacher.get_conn();
-        let key = format!("BILI_LIVE_ROOM_STATUS:{}:KEYFRAME", room_info.room_id);
-        let keyframe: String = conn.get(&key)?;
-        let () = conn.del(&key)?;
-        reqwest::Url::parse(&keyframe)?
-    } else {
-        reqwest::Url::parse(&room_info.cover_from_user)?
+    let chat_id = tg_type::ChatId(chat_id);
+    let caption = item.to_caption();
+    match item.cover().map(reqwest::Url::parse) {
+        Some(Ok(cover)) => {
+            ctx.bot
+                .send_photo(chat_id, tg_type::InputFile::url(cover))
+                .caption(caption)
+                .parse_mode(tg_type::ParseMode::Html)
+                .await?;
+        }
+        _ => {
+            ctx.bot
+                .send_message(chat_id, caption)
+                .parse_mode(tg_type::ParseMode::Html)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_find_bilibili_video() {
+    assert_eq!(
+        find_bvid("看 https://www.bilibili.com/video/BV1JB4y1s7Dk/?p=1"),
+        Some("BV1JB4y1s7Dk")
+    );
+    assert_eq!(find_bvid("BV1JB4y1s7Dk 好看"), Some("BV1JB4y1s7Dk"));
+    assert_eq!(find_bvid("看这个BV1JB4y1s7Dk好看"), Some("BV1JB4y1s7Dk"));
+    assert_eq!(find_bvid("BV1JB4y1s7Dkx"), None);
+    assert_eq!(find_bvid("ABV1JB4y1s7Dk"), None);
+    assert_eq!(find_bvid("BV1JB4y1s7"), None);
+    assert_eq!(
+        find_short_link("https://b23.tv/abc123?share=1"),
+        Some("https://b23.tv/abc123")
+    );
+    assert_eq!(find_short_link("https://b23.tv/"), None);
+}
+
+#[test]
+fn test_dynamic_item() {
+    let video: DynamicItem = serde_json::from_value(serde_json::json!({
+        "id_str": "1",
+        "type": "DYNAMIC_TYPE_AV",
+        "modules": {
+            "module_author": { "mid": 2, "name": "UP" },
+            "module_dynamic": {
+                "desc": null,
+                "major": { "archive": { "bvid": "BV1JB4y1s7Dk", "title": "<新视频>", "cover": "https://i0.hdslb.com/1.jpg" } }
+            }
+        }
+    }))
+    .unwrap();
+    assert!(video.is_video());
+    assert_eq!(video.cover(), Some("https://i0.hdslb.com/1.jpg"));
+    assert!(video.to_caption().contains("投稿了新视频"));
+    assert!(video.to_caption().contains("&lt;新视频&gt;"));
+
+    let post: DynamicItem = serde_json::from_value(serde_json::json!({
+        "id_str": "3",
+        "type": "DYNAMIC_TYPE_DRAW",
+        "modules": {
+            "module_author": { "mid": 2, "name": "UP" },
+            "module_dynamic": {
+                "desc": null,
+                "major": { "opus": { "summary": { "text": "你好" }, "pics": [{ "url": "https://i0.hdslb.com/2.jpg" }] } }
+            }
+        }
+    }))
+    .unwrap();
+    assert!(!post.is_video());
+    assert_eq!(post.text(), Some("你好"));
+    assert_eq!(post.cover(), Some("https://i0.hdslb.com/2.jpg"));
+    assert!(post.to_caption().contains("https://t.bilibili.com/3"));
+}
+
+#[test]
+fn test_rotation_ends_stream() {
+    use live::{status_change, StatusChange};
+
+    let room = |live_status: u8| -> RoomInfo {
+        serde_json::from_value(serde_json::json!({
+            "title": "title", "cover_from_user": "https://example.com/cover.jpg",
+            "live_status": live_status, "uname": "streamer", "area_v2_name": "area",
+            "room_id": 1, "uid": 2, "online": 0, "keyframe": "https://example.com/keyframe.jpg",
+        }))
+        .unwrap()
     };
 
-    let caption = room_info.to_captions(room_info.live_status);
-    if caption.is_none() {
-        return Ok(());
+    // The room goes into the rotation without stopping, and streams again after it
+    let mut prev = Some(false);
+    let mut changes = Vec::new();
+    for live_status in [1, 2, 1] {
+        let stream = room(live_status).to_live_stream();
+        changes.push(status_change(prev, stream.living));
+        prev = Some(stream.living);
     }
-    let caption = caption.unwrap();
-    ctx.bot
-        .send_photo(tg_type::ChatId(chat_id), tg_type::InputFile::url(cover))
-        .caption(caption)
-        .parse_mode(tg_type::ParseMode::Html)
-        .await?;
-    Ok(())
+    assert_eq!(
+        changes,
+        [
+            Some(StatusChange::Started),
+            Some(StatusChange::Stopped),
+            Some(StatusChange::Started)
+        ]
+    );
+    assert_eq!(status_change(None, room(2).to_live_stream().living), None);
 }
@@ -0,0 +1,248 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::CancellationToken;
+
+/// A FIFO queue that limits how many download jobs can run at the same time.
+#[derive(Clone)]
+pub struct DownloadQueue(Arc<QueueInner>);
+
+struct QueueInner {
+    workers: Arc<Semaphore>,
+    /// Every job is cancelled when this token is cancelled
+    shutdown: CancellationToken,
+    next_id: AtomicU64,
+    state: Mutex<QueueState>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    /// Jobs that are waiting for a worker, in enqueue order
+    waiting: VecDeque<u64>,
+    /// All the unfinished jobs, including the waiting and running jobs
+    jobs: HashMap<u64, JobEntry>,
+    /// Unfinished batches, the jobs of a batch are cancelled together with the batch
+    batches: HashMap<u64, JobEntry>,
+}
+
+struct JobEntry {
+    owner: u64,
+    cancel: CancellationToken,
+}
+
+impl DownloadQueue {
+    pub fn new(workers: usize, shutdown: CancellationToken) -> Self {
+        Self(Arc::new(QueueInner {
+            workers: Arc::new(Semaphore::new(workers.max(1))),
+            shutdown,
+            next_id: AtomicU64::new(0),
+            state: Mutex::new(QueueState::default()),
+        }))
+    }
+
+    /// Put a new job owned by the given user at the end of the queue.
+    pub fn enqueue(&self, owner: u64) -> DownloadJob {
+        self.enqueue_with_parent(owner, &self.0.shutdown)
+    }
+
+    /// Start a batch of jobs requested together by the given user, like the entries of a
+    /// playlist, so that they can be cancelled at once.
+    pub fn batch(&self, owner: u64) -> DownloadBatch {
+        let id = self.0.next_id.fetch_add(1, Ordering::Relaxed);
+        let cancel = self.0.shutdown.child_token();
+        let mut state = self.0.state.lock().unwrap();
+        state.batches.insert(
+            id,
+            JobEntry {
+                owner,
+                cancel: cancel.clone(),
+            },
+        );
+
+        DownloadBatch {
+            id,
+            owner,
+            queue: self.clone(),
+            cancel,
+        }
+    }
+
+    fn enqueue_with_parent(&self, owner: u64, parent: &CancellationToken) -> DownloadJob {
+        let id = self.0.next_id.fetch_add(1, Ordering::Relaxed);
+        let cancel = parent.child_token();
+
+        let mut state = self.0.state.lock().unwrap();
+        state.waiting.push_back(id);
+        state.jobs.insert(
+            id,
+            JobEntry {
+                owner,
+                cancel: cancel.clone(),
+            },
+        );
+
+        DownloadJob {
+            id,
+            queue: self.clone(),
+            cancel,
+            permit: None,
+        }
+    }
+
+    /// 1-based position of the job in the waiting list. Return `None` if the job is running or
+    /// finished.
+    pub fn position(&self, id: u64) -> Option<usize> {
+        let state = self.0.state.lock().unwrap();
+        state
+            .waiting
+            .iter()
+            .position(|job| *job == id)
+            .map(|index| index + 1)
+    }
+
+    /// Cancel all the batches and the waiting and running jobs owned by the given user, return
+    /// the number of cancelled tasks. A batch is counted as one task.
+    pub fn cancel_by_owner(&self, owner: u64) -> usize {
+        let state = self.0.state.lock().unwrap();
+        let owned_by = |entry: &&JobEntry| entry.owner == owner && !entry.cancel.is_cancelled();
+        // The jobs in the cancelled batches are cancelled as well, so they are not counted again
+        let batches = state
+            .batches
+            .values()
+            .filter(owned_by)
+            .inspect(|batch| batch.cancel.cancel())
+            .count();
+        let jobs = state
+            .jobs
+            .values()
+            .filter(owned_by)
+            .inspect(|job| job.cancel.cancel())
+            .count();
+        batches + jobs
+    }
+
+    fn remove_waiting(&self, id: u64) {
+        let mut state = self.0.state.lock().unwrap();
+        state.waiting.retain(|job| *job != id);
+    }
+
+    fn remove(&self, id: u64) {
+        let mut state = self.0.state.lock().unwrap();
+        state.waiting.retain(|job| *job != id);
+        state.jobs.remove(&id);
+    }
+}
+
+/// Handle of a job in the [`DownloadQueue`]. The job is removed from the queue and the worker is
+/// released when this handle is dropped.
+pub struct DownloadJob {
+    id: u64,
+    queue: DownloadQueue,
+    cancel: CancellationToken,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl DownloadJob {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Token that is cancelled when the owner cancel this job or the bot is shutting down
+    pub fn cancellation(&self) -> &CancellationToken {
+        &self.cancel
+    }
+
+    /// Wait until there is a free worker for this job. Return error if the job is cancelled before
+    /// it start running.
+    pub async fn wait_for_turn(&mut self) -> anyhow::Result<()> {
+        if self.permit.is_some() {
+            return Ok(());
+        }
+
+        let workers = Arc::clone(&self.queue.0.workers);
+        let permit = tokio::select! {
+            permit = workers.acquire_owned() => permit?,
+            _ = self.cancel.cancelled() => anyhow::bail!("Download task cancelled"),
+        };
+        self.queue.remove_waiting(self.id);
+        self.permit = Some(permit);
+
+        Ok(())
+    }
+}
+
+impl Drop for DownloadJob {
+    fn drop(&mut self) {
+        self.queue.remove(self.id);
+    }
+}
+
+/// Handle of a batch of jobs. The batch is removed from the queue when this handle is dropped.
+pub struct DownloadBatch {
+    id: u64,
+    owner: u64,
+    queue: DownloadQueue,
+    cancel: CancellationToken,
+}
+
+impl DownloadBatch {
+    /// Put a new job of this batch at the end of the queue
+    pub fn enqueue(&self) -> DownloadJob {
+        self.queue.enqueue_with_parent(self.owner, &self.cancel)
+    }
+
+    /// Token that is cancelled when the owner cancel this batch or the bot is shutting down
+    pub fn cancellation(&self) -> &CancellationToken {
+        &self.cancel
+    }
+}
+
+impl Drop for DownloadBatch {
+    fn drop(&mut self) {
+        let mut state = self.queue.0.state.lock().unwrap();
+        state.batches.remove(&self.id);
+    }
+}
+
+#[tokio::test]
+async fn test_download_queue() {
+    let shutdown = CancellationToken::new();
+    let queue = DownloadQueue::new(1, shutdown.clone());
+
+    let mut first = queue.enqueue(1);
+    let mut second = queue.enqueue(2);
+    let third = queue.enqueue(3);
+    assert_eq!(queue.position(first.id()), Some(1));
+    assert_eq!(queue.position(third.id()), Some(3));
+
+    first.wait_for_turn().await.unwrap();
+    assert_eq!(queue.position(first.id()), None);
+    assert_eq!(queue.position(second.id()), Some(1));
+    assert_eq!(queue.position(third.id()), Some(2));
+
+    // Only one worker, the second job must wait until the first job finish
+    let waiting =
+        tokio::time::timeout(std::time::Duration::from_millis(50), second.wait_for_turn()).await;
+    assert!(waiting.is_err());
+
+    assert_eq!(queue.cancel_by_owner(3), 1);
+    assert!(third.cancellation().is_cancelled());
+
+    drop(first);
+    second.wait_for_turn().await.unwrap();
+    assert_eq!(queue.position(second.id()), None);
+
+    let batch = queue.batch(5);
+    let fourth = batch.enqueue();
+    let fifth = batch.enqueue();
+    assert_eq!(queue.cancel_by_owner(5), 1);
+    assert!(fourth.cancellation().is_cancelled());
+    assert!(fifth.cancellation().is_cancelled());
+    assert!(batch.enqueue().cancellation().is_cancelled());
+
+    shutdown.cancel();
+    assert!(second.cancellation().is_cancelled());
+    assert!(queue.enqueue(4).cancellation().is_cancelled());
+}
@@ -0,0 +1,193 @@
+use crate::config::Config;
+use crate::helper::Html;
+use anyhow::Context;
+use serde_json::Value;
+use std::path::PathBuf;
+use std::process::Stdio;
+use teloxide::types::InputFile;
+use teloxide::utils::html::escape;
+use tempfile::TempDir;
+use tokio::process;
+
+use super::video_dl::{DownloadOptions, MediaFile, MediaKind, VideoDownloader};
+
+/// Max characters of the post content put into the caption
+const CAPTION_CONTENT_LENGTH: usize = 200;
+
+/// Images and videos of a post downloaded by gallery-dl
+#[derive(Debug)]
+pub struct GalleryDlPost {
+    pub url: String,
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub files: Vec<(PathBuf, MediaKind)>,
+    workdir: Option<TempDir>,
+}
+
+impl GalleryDlPost {
+    pub async fn dl_from_url(url: &str) -> anyhow::Result<Self> {
+        let info = gallery_dl_command()
+            .arg("--dump-json")
+            .arg(url)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .output()
+            .await?;
+        if !info.status.success() {
+            anyhow::bail!("{}", String::from_utf8_lossy(&info.stderr));
+        }
+        let info: Value = serde_json::from_slice(&info.stdout)
+            .with_context(|| "fail to parse gallery-dl metadata")?;
+        let (title, author) = post_title_and_author(&info);
+
+        let workdir = tempfile::Builder::new()
+            .prefix("tg-maid-gallery-dl-")
+            .tempdir()
+            .with_context(|| "fail to create working directory for gallery-dl")?;
+        let limit_mb = Config::get_global_config().upload_limit_mb();
+        let output = gallery_dl_command()
+            .arg("--directory")
+            .arg(workdir.path())
+            .arg("--filesize-max")
+            .arg(format!("{limit_mb}M"))
+            .arg(url)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .output()
+            .await?;
+        if !output.status.success() {
+            anyhow::bail!("{}", String::from_utf8_lossy(&output.stderr));
+        }
+
+        // gallery-dl prints the path of every downloaded file in the download order
+        let files: Vec<_> = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(PathBuf::from)
+            .filter(|path| path.starts_with(workdir.path()) && path.exists())
+            .filter_map(|path| {
+                let kind = MediaKind::from_path(&path)?;
+                Some((path, kind))
+            })
+            .collect();
+        if files.is_empty() {
+            anyhow::bail!("No image or video found in this post");
+        }
+
+        Ok(Self {
+            url: url.to_string(),
+            title,
+            author,
+            files,
+            workdir: Some(workdir),
+        })
+    }
+
+    pub fn as_tg_caption(&self) -> String {
+        let title = match &self.title {
+            Some(title) => escape(
+                &title
+                    .chars()
+                    .take(CAPTION_CONTENT_LENGTH)
+                    .collect::<String>(),
+            ),
+            None => escape(&self.url),
+        };
+        let mut caption = Html::a(&self.url, &title);
+        if let Some(author) = &self.author {
+            caption.push_str(&format!("\n作者：{}", escape(author)));
+        }
+        caption
+    }
+
+    pub async fn clean(self) -> anyhow::Result<()> {
+        if let Some(workdir) = self.workdir {
+            tokio::task::spawn_blocking(move || workdir.close())
+                .await?
+                .with_context(|| "fail to delete gallery-dl working directory")?;
+        }
+        Ok(())
+    }
+}
+
+/// Find the post title and author from the `--dump-json` output, which is a list of messages like
+/// `[2, {metadata}]` for directory and `[3, "url", {metadata}]` for file.
+fn post_title_and_author(info: &Value) -> (Option<String>, Option<String>) {
+    let Some(metadata) = info
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|message| message.as_array()?.last())
+        .find(|metadata| metadata.is_object())
+    else {
+        return (None, None);
+    };
+
+    let text = |value: &Value| {
+        value
+            .as_str()
+            .map(str::trim)
+            .filter(|text| !text.is_empty())
+            .map(String::from)
+    };
+    let title = ["title", "content", "description"]
+        .iter()
+        .find_map(|key| text(&metadata[key]));
+    let author = ["author", "user"]
+        .iter()
+        .find_map(|key| text(&metadata[key]["name"]));
+    (title, author)
+}
+
+fn gallery_dl_command() -> process::Command {
+    use which::which;
+    let gallery_dl = which("gallery-dl").expect("can not found gallery-dl program");
+    let mut command = process::Command::new(gallery_dl);
+    if let Some(proxy_url) = Config::get_global_config().proxy.gallery_dl() {
+        command.arg("--proxy").arg(proxy_url);
+    }
+    command
+}
+
+impl VideoDownloader for GalleryDlPost {
+    async fn download_from_url(u: &str, _options: DownloadOptions) -> anyhow::Result<Self> {
+        Self::dl_from_url(u).await
+    }
+
+    fn provide_caption(&self) -> String {
+        self.as_tg_caption()
+    }
+
+    fn media_files(&self) -> Vec<MediaFile> {
+        self.files
+            .iter()
+            .map(|(path, kind)| MediaFile::new(InputFile::file(path), *kind))
+            .collect()
+    }
+
+    async fn clean(self) -> anyhow::Result<()> {
+        GalleryDlPost::clean(self).await
+    }
+}
+
+#[test]
+fn test_post_title_and_author() {
+    let info = serde_json::json!([
+        [2, { "content": " Hello ", "author": { "name": "alice", "nick": "Alice" } }],
+        [3, "https://pbs.twimg.com/media/1.jpg", { "num": 1 }],
+    ]);
+    assert_eq!(
+        post_title_and_author(&info),
+        (Some("Hello".to_string()), Some("alice".to_string()))
+    );
+
+    let info = serde_json::json!([
+        [3, "https://i.pximg.net/1.png", { "title": "Art", "user": { "name": "bob" } }],
+    ]);
+    assert_eq!(
+        post_title_and_author(&info),
+        (Some("Art".to_string()), Some("bob".to_string()))
+    );
+
+    assert_eq!(post_title_and_author(&serde_json::json!([])), (None, None));
+}
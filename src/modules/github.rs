@@ -0,0 +1,386 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+use super::watch_list::{Update, WatchList};
+use crate::http::HttpClient;
+use crate::{app::AppData, config::Config, event::EventWatcher, helper::Html};
+use redis::Commands;
+use reqwest::{header, StatusCode};
+use serde::Deserialize;
+use teloxide::utils::html::escape;
+
+/// Name of the watcher, which is also the event name of the subscribe registry
+pub const GITHUB_WATCHER: &str = "GithubRepoWatcher";
+/// Repositories watched by the chats, keyed by `owner/repo:kind`
+const REPOS: WatchList = WatchList {
+    watcher: GITHUB_WATCHER,
+    key_prefix: "GITHUB",
+};
+/// Max characters of the release note put into the message
+const RELEASE_BODY_LENGTH: usize = 800;
+
+lazy_static::lazy_static!(
+    static ref MATCH_REPO: regex::Regex =
+        regex::Regex::new(r"^[A-Za-z0-9-]+/[A-Za-z0-9._-]+$").unwrap();
+);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WatchKind {
+    #[default]
+    Releases,
+    Tags,
+    Commits,
+}
+
+impl FromStr for WatchKind {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "releases" => Ok(Self::Releases),
+            "tags" => Ok(Self::Tags),
+            "commits" => Ok(Self::Commits),
+            _ => anyhow::bail!("unknown kind {s}, expect releases, tags or commits"),
+        }
+    }
+}
+
+impl Display for WatchKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self {
+            Self::Releases => "releases",
+            Self::Tags => "tags",
+            Self::Commits => "commits",
+        };
+        f.write_str(kind)
+    }
+}
+
+/// Updates of a repository watched for the chats, stored as `owner/repo:kind` in the registry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepoWatch {
+    pub repo: String,
+    pub kind: WatchKind,
+}
+
+impl RepoWatch {
+    pub fn new(repo: &str, kind: Option<&str>) -> anyhow::Result<Self> {
+        let repo = repo
+            .trim_start_matches("https://github.com/")
+            .trim_end_matches('/');
+        if !MATCH_REPO.is_match(repo) {
+            anyhow::bail!("invalid repository {repo}, expect owner/repo");
+        }
+        Ok(Self {
+            repo: repo.to_string(),
+            kind: kind
+                .map(str::parse::<WatchKind>)
+                .transpose()?
+                .unwrap_or_default(),
+        })
+    }
+
+    fn api_url(&self) -> String {
+        format!(
+            "https://api.github.com/repos/{}/{}?per_page=10",
+            self.repo, self.kind
+        )
+    }
+}
+
+impl FromStr for RepoWatch {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (repo, kind) = s
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow::anyhow!("invalid watch event {s}"))?;
+        Self::new(repo, Some(kind))
+    }
+}
+
+impl Display for RepoWatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.repo, self.kind)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct Release {
+    id: u64,
+    name: Option<String>,
+    tag_name: String,
+    html_url: String,
+    body: Option<String>,
+    #[serde(default)]
+    draft: bool,
+    #[serde(default)]
+    prerelease: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct Tag {
+    name: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Commit {
+    sha: String,
+    html_url: String,
+    commit: CommitDetail,
+}
+
+#[derive(Deserialize, Debug)]
+struct CommitDetail {
+    message: String,
+    author: Option<CommitAuthor>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CommitAuthor {
+    name: String,
+}
+
+/// Cut the Markdown at the last line break within the limit, so the rendered HTML keeps balanced
+fn trim_markdown(markdown: &str, limit: usize) -> String {
+    let Some((end, _)) = markdown.char_indices().nth(limit) else {
+        return markdown.to_string();
+    };
+    let trimmed = &markdown[..end];
+    let trimmed = trimmed.rsplit_once('\n').map_or(trimmed, |(line, _)| line);
+    format!("{}\n…", trimmed.trim_end())
+}
+
+impl Release {
+    fn to_update(&self, repo: &str) -> Update {
+        let name = self
+            .name
+            .as_deref()
+            .filter(|name| !name.trim().is_empty())
+            .unwrap_or(&self.tag_name);
+        let mut message = format!(
+            "{} released {}",
+            Html::b(repo),
+            Html::a(&escape(&self.html_url), &escape(name))
+        );
+        if self.prerelease {
+            message.push_str(" (pre-release)");
+        }
+        message.push_str(&format!("\nTag: {}", Html::code(escape(&self.tag_name))));
+
+        let body = trim_markdown(
+            self.body.as_deref().unwrap_or_default(),
+            RELEASE_BODY_LENGTH,
+        );
+        let body = Html::from_markdown(&body);
+        if !body.is_empty() {
+            message.push_str(&format!("\n\n{body}"));
+        }
+        Update {
+            id: self.id.to_string(),
+            message,
+        }
+    }
+}
+
+impl Tag {
+    fn to_update(&self, repo: &str) -> Update {
+        let url = format!("https://github.com/{repo}/releases/tag/{}", self.name);
+        Update {
+            id: self.name.clone(),
+            message: format!(
+                "{} tagged {}",
+                Html::b(repo),
+                Html::a(&escape(&url), &escape(&self.name))
+            ),
+        }
+    }
+}
+
+impl Commit {
+    fn to_update(&self, repo: &str) -> Update {
+        let short_sha: String = self.sha.chars().take(7).collect();
+        let mut message = format!(
+            "{} new commit {}",
+            Html::b(repo),
+            Html::a(&escape(&self.html_url), &Html::code(short_sha))
+        );
+        if let Some(author) = &self.commit.author {
+            message.push_str(&format!(" by {}", escape(&author.name)));
+        }
+        let summary = self.commit.message.lines().next().unwrap_or_default();
+        message.push_str(&format!("\n{}", escape(summary)));
+        Update {
+            id: self.sha.clone(),
+            message,
+        }
+    }
+}
+
+fn parse_updates(watch: &RepoWatch, json: &str) -> anyhow::Result<Vec<Update>> {
+    let updates = match watch.kind {
+        WatchKind::Releases => serde_json::from_str::<Vec<Release>>(json)?
+            .iter()
+            .filter(|release| !release.draft)
+            .map(|release| release.to_update(&watch.repo))
+            .collect(),
+        WatchKind::Tags => serde_json::from_str::<Vec<Tag>>(json)?
+            .iter()
+            .map(|tag| tag.to_update(&watch.repo))
+            .collect(),
+        WatchKind::Commits => serde_json::from_str::<Vec<Commit>>(json)?
+            .iter()
+            .map(|commit| commit.to_update(&watch.repo))
+            .collect(),
+    };
+    Ok(updates)
+}
+
+/// Fetch the latest updates in the order from newest to oldest, return `None` if nothing changed
+/// since the last fetch. Conditional requests are not counted in the GitHub rate limit.
+pub async fn fetch_updates(
+    data: &AppData,
+    client: &HttpClient,
+    watch: &RepoWatch,
+) -> anyhow::Result<Option<Vec<Update>>> {
+    let cache_key = REPOS.http_cache_key(&watch.to_string());
+    let etag: Option<String> = data.cacher.get_conn().get(&cache_key)?;
+
+    let mut request = client
+        .get(watch.api_url())
+        .header(header::USER_AGENT, "rusty-maid")
+        .header(header::ACCEPT, "application/vnd.github+json");
+    if let Some(github) = &Config::get_global_config().github {
+        request = request.bearer_auth(&github.token);
+    }
+    if let Some(etag) = etag {
+        request = request.header(header::IF_NONE_MATCH, etag);
+    }
+    let response = request.send().await?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
+    let response = response.error_for_status()?;
+    let etag = response
+        .headers()
+        .get(header::ETAG)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+
+    let updates = parse_updates(watch, &response.text().await?)?;
+    if let Some(etag) = etag {
+        let () = data.cacher.get_conn().set(&cache_key, etag)?;
+    }
+    Ok(Some(updates))
+}
+
+/// Watch the repository for the chat after checking that it exists
+pub async fn watch_repo(data: &AppData, chat_id: i64, watch: &RepoWatch) -> anyhow::Result<()> {
+    REPOS
+        .watch(data, chat_id, &watch.to_string(), async {
+            let updates = fetch_updates(data, &data.requester, watch)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("unexpected not modified response"))?;
+            Ok(((), updates))
+        })
+        .await?;
+    Ok(())
+}
+
+/// Repositories watched by the chat, sorted by name
+pub fn list_watches(data: &AppData, chat_id: i64) -> anyhow::Result<Vec<RepoWatch>> {
+    let watches = REPOS
+        .list(data, chat_id)?
+        .iter()
+        .filter_map(|event| event.parse().ok())
+        .collect();
+    Ok(watches)
+}
+
+/// Stop watching the repository for the chat, return `false` if it is not watched
+pub fn unwatch_repo(data: &AppData, chat_id: i64, watch: &RepoWatch) -> anyhow::Result<bool> {
+    REPOS.unwatch(data, chat_id, &watch.to_string())
+}
+
+pub fn spawn_github_listener(bot: teloxide::Bot, data: AppData) {
+    EventWatcher::builder()
+        .name(GITHUB_WATCHER)
+        .bot(bot)
+        .data(data)
+        .client(None)
+        .heartbeat_interval(600) // 10mins
+        .build()
+        .start_with_task(watch_and_response);
+}
+
+async fn watch_and_response(ctx: EventWatcher<()>) -> anyhow::Result<()> {
+    let data = &ctx.data;
+    let client = ctx.client.as_ref().unwrap_or(&data.requester);
+    REPOS
+        .poll(&ctx, |event| async move {
+            let watch: RepoWatch = event.parse()?;
+            fetch_updates(data, client, &watch).await
+        })
+        .await
+}
+
+#[test]
+fn test_repo_watch() {
+    let watch = RepoWatch::new("https://github.com/rust-lang/rust/", None).unwrap();
+    assert_eq!(watch.repo, "rust-lang/rust");
+    assert_eq!(watch.kind, WatchKind::Releases);
+    assert_eq!(watch.to_string(), "rust-lang/rust:releases");
+    assert_eq!(
+        "rust-lang/rust:releases".parse::<RepoWatch>().unwrap(),
+        watch
+    );
+    assert_eq!(
+        watch.api_url(),
+        "https://api.github.com/repos/rust-lang/rust/releases?per_page=10"
+    );
+
+    assert!(RepoWatch::new("rust-lang", None).is_err());
+    assert!(RepoWatch::new("rust-lang/rust", Some("issues")).is_err());
+}
+
+#[test]
+fn test_parse_updates() {
+    let watch = RepoWatch::new("a/b", Some("releases")).unwrap();
+    let json = serde_json::json!([
+        {
+            "id": 2, "name": "", "tag_name": "v2.0", "html_url": "https://github.com/a/b/releases/tag/v2.0",
+            "body": "## Changes\n* **new** feature", "draft": false, "prerelease": true,
+        },
+        {
+            "id": 1, "name": "Draft", "tag_name": "v1.0", "html_url": "https://github.com/a/b/releases/tag/v1.0",
+            "body": null, "draft": true, "prerelease": false,
+        },
+    ]);
+    let updates = parse_updates(&watch, &json.to_string()).unwrap();
+    assert_eq!(
+        updates,
+        [Update {
+            id: "2".to_string(),
+            message:
+                "<b>a/b</b> released <a href=\"https://github.com/a/b/releases/tag/v2.0\">v2.0</a> \
+                (pre-release)\nTag: <code>v2.0</code>\n\n<b>Changes</b>\n• <b>new</b> feature"
+                    .to_string(),
+        }]
+    );
+
+    let watch = RepoWatch::new("a/b", Some("commits")).unwrap();
+    let json = serde_json::json!([{
+        "sha": "0123456789abcdef", "html_url": "https://github.com/a/b/commit/0123456789abcdef",
+        "commit": { "message": "Fix <bug>\n\nDetails", "author": { "name": "alice" } },
+    }]);
+    let updates = parse_updates(&watch, &json.to_string()).unwrap();
+    assert_eq!(
+        updates[0].message,
+        "<b>a/b</b> new commit <a href=\"https://github.com/a/b/commit/0123456789abcdef\">\
+        <code>0123456</code></a> by alice\nFix &lt;bug&gt;"
+    );
+}
+
+#[test]
+fn test_trim_markdown() {
+    assert_eq!(trim_markdown("short", 10), "short");
+    assert_eq!(trim_markdown("line 1\nline 2\nline 3", 10), "line 1\n…");
+}
@@ -1,35 +1,160 @@
-use tokio::{io::AsyncWriteExt, net::TcpListener};
+use std::time::Duration;
 
-/// Spawn a health check listener in a non-blocking task for Docker HEALTHCHECK task
-pub fn spawn_healthcheck_listner(port: u16) {
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{app::AppData, metrics};
+
+/// Max bytes read from the request, only the request line is used
+const MAX_REQUEST_SIZE: usize = 8192;
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn text(status: &'static str, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.into(),
+        }
+    }
+
+    /// Serialize the response, the body is left out for the `HEAD` requests
+    fn to_bytes(&self, with_body: bool) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.status,
+            self.content_type,
+            self.body.len(),
+            if with_body { self.body.as_str() } else { "" }
+        )
+        .into_bytes()
+    }
+}
+
+/// Spawn a HTTP server in a non-blocking task for Docker HEALTHCHECK and Prometheus, which stops
+/// when the bot is shutting down:
+///
+/// * `/healthz`: Redis is connectable and Telegram polling works
+/// * `/readyz`: The bot is not shutting down
+/// * `/metrics`: Metrics in the Prometheus text format
+pub fn spawn_healthcheck_listner(host: &str, port: u16, data: AppData) {
+    let host = host.to_string();
     tokio::task::spawn(async move {
-        let listener = TcpListener::bind(("127.0.0.1", port))
+        let listener = TcpListener::bind((host.as_str(), port))
             .await
             .expect("fail to bind docker health listener");
 
-        tracing::info!("Docker health check listening on port {port}");
+        tracing::info!("Health check listening on {host}:{port}");
 
-        while let Ok((mut stream, _)) = listener.accept().await {
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = data.shutdown.cancelled() => break,
+            };
+            let Ok((stream, _)) = accepted else {
+                break;
+            };
             tracing::debug!("New Stream Incoming");
-            let res = stream.write_all(b"OK").await;
-            if let Err(err) = res {
-                tracing::error!("fail to response to health checker: {err}")
-            }
+            let data = data.clone();
+            tokio::spawn(async move {
+                if let Err(err) = serve(stream, &data).await {
+                    tracing::error!("fail to response to health checker: {err}")
+                }
+            });
         }
     });
 }
 
-#[tokio::test]
-async fn test_healthcheck() {
-    spawn_healthcheck_listner(11451);
+async fn serve(mut stream: TcpStream, data: &AppData) -> anyhow::Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    let read_request_head = async {
+        while !request.windows(4).any(|window| window == b"\r\n\r\n")
+            && request.len() < MAX_REQUEST_SIZE
+        {
+            let size = stream.read(&mut buffer).await?;
+            if size == 0 {
+                break;
+            }
+            request.extend_from_slice(&buffer[..size]);
+        }
+        anyhow::Ok(())
+    };
+    tokio::time::timeout(Duration::from_secs(5), read_request_head).await??;
+    // Port probes connect and close without sending anything
+    if request.is_empty() {
+        return Ok(());
+    }
+
+    let request = parse_request(&request);
+    let response = match request.map(|(_, path)| path) {
+        Some("/healthz") => healthz(data),
+        Some("/readyz") if data.shutdown.is_cancelled() => {
+            Response::text("503 Service Unavailable", "shutting down")
+        }
+        Some("/readyz") => Response::text("200 OK", "OK"),
+        Some("/metrics") => Response {
+            status: "200 OK",
+            content_type: "text/plain; version=0.0.4",
+            body: metrics::render(),
+        },
+        Some(_) => Response::text("404 Not Found", "not found"),
+        None => Response::text("400 Bad Request", "bad request"),
+    };
+    let with_body = !matches!(request, Some(("HEAD", _)));
+    stream.write_all(&response.to_bytes(with_body)).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+fn healthz(data: &AppData) -> Response {
+    if let Err(err) = data.cacher.ping() {
+        return Response::text("503 Service Unavailable", format!("redis: {err}"));
+    }
+    if !metrics::telegram_polling_healthy() {
+        return Response::text("503 Service Unavailable", "telegram: polling failed");
+    }
+    Response::text("200 OK", "OK")
+}
+
+/// Method and path without the query string of a `GET` or `HEAD` request
+fn parse_request(request: &[u8]) -> Option<(&str, &str)> {
+    let request = std::str::from_utf8(request).ok()?;
+    let mut request_line = request.lines().next()?.split_whitespace();
+    let (method, target) = (request_line.next()?, request_line.next()?);
+    if method != "GET" && method != "HEAD" {
+        return None;
+    }
+    Some((method, target.split('?').next()?))
+}
 
-    tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+#[test]
+fn test_parse_request() {
+    assert_eq!(
+        parse_request(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+        Some(("GET", "/metrics"))
+    );
+    assert_eq!(
+        parse_request(b"HEAD /healthz?verbose=1 HTTP/1.1\r\n\r\n"),
+        Some(("HEAD", "/healthz"))
+    );
+    assert_eq!(parse_request(b"POST /healthz HTTP/1.1\r\n\r\n"), None);
+    assert_eq!(parse_request(b""), None);
 
-    use tokio::process::Command;
-    let op = Command::new("nc")
-        .args(["127.0.0.1", "11451"])
-        .output()
-        .await
-        .expect("fail to execute netcat");
-    assert_eq!("OK", String::from_utf8(op.stdout).unwrap());
+    let response = Response::text("200 OK", "OK");
+    assert_eq!(
+        response.to_bytes(true),
+        b"HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\n\
+        Content-Length: 2\r\nConnection: close\r\n\r\nOK"
+    );
+    assert!(response
+        .to_bytes(false)
+        .ends_with(b"Content-Length: 2\r\nConnection: close\r\n\r\n"));
 }
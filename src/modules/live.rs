@@ -0,0 +1,476 @@
+use crate::{app::AppData, event::EventWatcher};
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+use teloxide::{
+    payloads::{EditMessageCaptionSetters, SendPhotoSetters},
+    prelude::Requester,
+    types as tg_type,
+};
+
+/// Platforms that provide live stream notifications
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LivePlatform {
+    Bilibili,
+    Twitch,
+    Youtube,
+}
+
+impl LivePlatform {
+    /// Prefix of all the redis keys used by the watcher of this platform
+    fn key_prefix(&self) -> &'static str {
+        match self {
+            Self::Bilibili => "BILI_LIVE_ROOM",
+            Self::Twitch => "TWITCH_LIVE_ROOM",
+            Self::Youtube => "YOUTUBE_LIVE_ROOM",
+        }
+    }
+
+    fn tag(&self) -> &'static str {
+        match self {
+            Self::Bilibili => "BiliLiveRoom",
+            Self::Twitch => "TwitchLive",
+            Self::Youtube => "YoutubeLive",
+        }
+    }
+}
+
+/// Status of a live room, converted from the platform specific API response
+#[derive(Debug, Clone)]
+pub struct LiveStream {
+    pub platform: LivePlatform,
+    /// Unique id of the room in the platform
+    pub room_id: String,
+    pub streamer: String,
+    pub streamer_url: String,
+    pub title: String,
+    pub url: String,
+    pub living: bool,
+    /// Online count, `None` if the platform doesn't provide it
+    pub online: Option<u64>,
+    pub area: Option<String>,
+    /// Cover image URL
+    pub cover: String,
+    /// Latest screenshot of the stream, used as the cover when the stream ends
+    pub keyframe: Option<String>,
+    /// Unix timestamp when the stream started
+    pub start_time: Option<i64>,
+}
+
+impl LiveStream {
+    pub fn to_captions(&self, session: Option<&LiveSession>) -> String {
+        use teloxide::utils::html::escape;
+        if !self.living {
+            return match session {
+                Some(session) => format!(
+                    "{} 已下播\n{}",
+                    escape(&self.streamer),
+                    session.summary(chrono::Utc::now().timestamp())
+                ),
+                None => format!("{} 已下播", escape(&self.streamer)),
+            };
+        }
+
+        let mut caption = format!(
+            "<a href=\"{}\">{}</a> 开播了！",
+            self.streamer_url,
+            escape(&self.streamer)
+        );
+        if let Some(online) = self.online {
+            caption.push_str(&format!("已有 {online} 人正在观看"));
+        }
+        caption.push_str(&format!(
+            "\n直播: <a href=\"{}\">{}</a>\n",
+            self.url,
+            escape(&self.title)
+        ));
+        if let Some(area) = self.area.as_ref().filter(|area| !area.is_empty()) {
+            caption.push_str(&format!("分区: #{}\n", escape(area)));
+        }
+        caption
+    }
+
+    fn status_key(&self) -> String {
+        format!("{}_STATUS:{}", self.platform.key_prefix(), self.room_id)
+    }
+
+    fn keyframe_key(&self) -> String {
+        format!(
+            "{}_STATUS:{}:KEYFRAME",
+            self.platform.key_prefix(),
+            self.room_id
+        )
+    }
+
+    fn session_key(&self) -> String {
+        format!("{}_SESSION:{}", self.platform.key_prefix(), self.room_id)
+    }
+
+    fn notification_key(&self, chat_id: i64) -> String {
+        format!(
+            "{}_MESSAGE:{chat_id}:{}",
+            self.platform.key_prefix(),
+            self.room_id
+        )
+    }
+}
+
+/// Seconds to keep the session after the last time the room is seen living
+const LIVE_SESSION_TTL_SECONDS: u64 = 60 * 60 * 24;
+
+/// Statistics of a live stream session, recorded while the room is living
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct LiveSession {
+    /// Unix timestamp when the stream started
+    pub start: i64,
+    /// Peak online count
+    pub peak: u64,
+    /// Room titles in the order they were used
+    pub titles: Vec<String>,
+}
+
+impl LiveSession {
+    /// Update the recorded session with the living room, or start a new session if there is none,
+    /// or the recorded one belongs to a previous stream whose end was missed.
+    fn continue_with(session: Option<Self>, stream: &LiveStream) -> Self {
+        let start_time = stream.start_time.filter(|start| *start > 0);
+        let mut session = match session {
+            Some(session) if start_time.is_none_or(|start| start == session.start) => session,
+            _ => Self {
+                start: start_time.unwrap_or_else(|| chrono::Utc::now().timestamp()),
+                ..Default::default()
+            },
+        };
+        session.update(stream);
+        session
+    }
+
+    /// Record the latest room status into the session
+    fn update(&mut self, stream: &LiveStream) {
+        self.peak = self.peak.max(stream.online.unwrap_or_default());
+        if self.titles.last() != Some(&stream.title) {
+            self.titles.push(stream.title.clone());
+        }
+    }
+
+    fn summary(&self, end: i64) -> String {
+        use teloxide::utils::html::escape;
+        let duration = (end - self.start).max(0);
+        let mut summary = format!(
+            "本次直播时长：{}:{:02}:{:02}",
+            duration / 3600,
+            duration % 3600 / 60,
+            duration % 60,
+        );
+        if self.peak > 0 {
+            summary.push_str(&format!("\n最高人气：{}", self.peak));
+        }
+        if self.titles.len() > 1 {
+            let titles = self
+                .titles
+                .iter()
+                .map(|title| escape(title))
+                .collect::<Vec<_>>()
+                .join(" → ");
+            summary.push_str(&format!("\n标题变更：{titles}"));
+        }
+        summary
+    }
+}
+
+/// Cache the live status, return the previous status. `None` indicate that the status is not exist
+/// before.
+pub fn cache_live_status(data: &AppData, stream: &LiveStream) -> anyhow::Result<Option<bool>> {
+    let key = stream.status_key();
+    let mut conn = data.cacher.get_conn();
+    let prev_status: Option<u8> = conn.get(&key)?;
+
+    let () = conn.set(&key, stream.living as u8)?;
+
+    if stream.living {
+        if let Some(keyframe) = &stream.keyframe {
+            let () = conn.set(stream.keyframe_key(), keyframe)?;
+        }
+    }
+
+    Ok(prev_status.map(|status| status == 1))
+}
+
+/// Start or update the live session of the living room
+pub fn record_live_session(data: &AppData, stream: &LiveStream) -> anyhow::Result<()> {
+    let key = stream.session_key();
+    let mut conn = data.cacher.get_conn();
+    let session: Option<String> = conn.get(&key)?;
+    let session = match session {
+        Some(session) => Some(serde_json::from_str(&session)?),
+        None => None,
+    };
+    let session = LiveSession::continue_with(session, stream);
+    // The session is left behind if the end of the stream is missed, don't keep it forever
+    let () = conn.set_ex(
+        &key,
+        serde_json::to_string(&session)?,
+        LIVE_SESSION_TTL_SECONDS,
+    )?;
+    Ok(())
+}
+
+/// Remove and return the session of the room that has stopped streaming
+pub fn take_live_session(
+    data: &AppData,
+    stream: &LiveStream,
+) -> anyhow::Result<Option<LiveSession>> {
+    let key = stream.session_key();
+    let mut conn = data.cacher.get_conn();
+    let session: Option<String> = conn.get(&key)?;
+    let () = conn.del(&key)?;
+    Ok(match session {
+        Some(session) => Some(serde_json::from_str(&session)?),
+        None => None,
+    })
+}
+
+/// The last keyframe for the stopped stream, or the room cover for the living stream
+fn live_room_cover(data: &AppData, stream: &LiveStream) -> anyhow::Result<reqwest::Url> {
+    if !stream.living {
+        let mut conn = data.cacher.get_conn();
+        let key = stream.keyframe_key();
+        let keyframe: Option<String> = conn.get(&key)?;
+        let () = conn.del(&key)?;
+        if let Some(keyframe) = keyframe {
+            return Ok(reqwest::Url::parse(&keyframe)?);
+        }
+    }
+    Ok(reqwest::Url::parse(&stream.cover)?)
+}
+
+/// Change of the stream status between two polls that needs the notifications to be sent or edited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusChange {
+    Started,
+    /// Still living, the notification is updated with the latest information
+    Updated,
+    Stopped,
+}
+
+/// Compare the cached living status with the current one, return `None` if nothing to notify
+pub fn status_change(prev_living: Option<bool>, living: bool) -> Option<StatusChange> {
+    match (prev_living, living) {
+        (Some(true), true) => Some(StatusChange::Updated),
+        (_, true) => Some(StatusChange::Started),
+        (Some(true), false) => Some(StatusChange::Stopped),
+        // A stream seen for the first time is offline since before the bot watches it, which is
+        // not a status change
+        (None | Some(false), false) => None,
+    }
+}
+
+/// Notify the subscribers of each stream when the stream starts or ends, and keep the notification
+/// updated while the stream is living. Each stream comes with the event it is subscribed by.
+pub async fn process_live_streams<S, Event>(
+    ctx: &EventWatcher<S>,
+    streams: impl IntoIterator<Item = (Event, LiveStream)>,
+) -> anyhow::Result<()>
+where
+    S: Send + Sync + 'static,
+    Event: redis::ToRedisArgs + std::fmt::Display,
+{
+    for (event, stream) in streams {
+        let tag = stream.platform.tag();
+        let prev_status = match cache_live_status(&ctx.data, &stream) {
+            Ok(status) => status,
+            Err(err) => {
+                tracing::error!("[{tag}] fail to update cache: {err}");
+                continue;
+            }
+        };
+
+        if stream.living {
+            if let Err(err) = record_live_session(&ctx.data, &stream) {
+                tracing::error!("[{tag}] fail to record live session: {err}");
+            }
+        }
+
+        let Some(change) = status_change(prev_status, stream.living) else {
+            continue;
+        };
+
+        let session = if change == StatusChange::Stopped {
+            take_live_session(&ctx.data, &stream).unwrap_or_else(|err| {
+                tracing::error!("[{tag}] fail to read live session: {err}");
+                None
+            })
+        } else {
+            None
+        };
+        let cover = match live_room_cover(&ctx.data, &stream) {
+            Ok(cover) => cover,
+            Err(err) => {
+                tracing::error!("[{tag}] fail to get cover: {err}");
+                continue;
+            }
+        };
+
+        let subscribers: Vec<i64> = ctx.get_subscribers(&event)?;
+        for chat_id in subscribers {
+            let result = match change {
+                StatusChange::Updated => update_live_notification(ctx, chat_id, &stream).await,
+                StatusChange::Started | StatusChange::Stopped => {
+                    notify_live_changes(ctx, chat_id, &stream, &cover, session.as_ref()).await
+                }
+            };
+            if let Err(err) = result {
+                tracing::error!("[{tag}] fail to notify changes: {err}")
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Send the notification when the stream starts, or edit the notification sent at the start into
+/// the stopped state when the stream ends.
+async fn notify_live_changes<S>(
+    ctx: &EventWatcher<S>,
+    chat_id: i64,
+    stream: &LiveStream,
+    cover: &reqwest::Url,
+    session: Option<&LiveSession>,
+) -> anyhow::Result<()> {
+    let caption = stream.to_captions(session);
+
+    let key = stream.notification_key(chat_id);
+    let mut conn = ctx.data.cacher.get_conn();
+    let chat = tg_type::ChatId(chat_id);
+    let sent_message: Option<i32> = conn.get(&key)?;
+
+    if !stream.living {
+        let () = conn.del(&key)?;
+        if let Some(message_id) = sent_message {
+            let media = tg_type::InputMedia::Photo(
+                tg_type::InputMediaPhoto::new(tg_type::InputFile::url(cover.clone()))
+                    .caption(caption.as_str())
+                    .parse_mode(tg_type::ParseMode::Html),
+            );
+            let edited = ctx
+                .bot
+                .edit_message_media(chat, tg_type::MessageId(message_id), media)
+                .await;
+            match edited {
+                Ok(_) => return Ok(()),
+                // The message might be deleted, send a new one instead
+                Err(err) => tracing::warn!("fail to edit live notification: {err}"),
+            }
+        }
+    }
+
+    let sent = ctx
+        .bot
+        .send_photo(chat, tg_type::InputFile::url(cover.clone()))
+        .caption(caption)
+        .parse_mode(tg_type::ParseMode::Html)
+        .await?;
+    if stream.living {
+        let () = conn.set(&key, sent.id.0)?;
+    }
+    Ok(())
+}
+
+/// Update the online count and title in the notification while the stream is living
+async fn update_live_notification<S>(
+    ctx: &EventWatcher<S>,
+    chat_id: i64,
+    stream: &LiveStream,
+) -> anyhow::Result<()> {
+    let sent_message: Option<i32> = ctx
+        .data
+        .cacher
+        .get_conn()
+        .get(stream.notification_key(chat_id))?;
+    let Some(message_id) = sent_message else {
+        return Ok(());
+    };
+
+    let result = ctx
+        .bot
+        .edit_message_caption(tg_type::ChatId(chat_id), tg_type::MessageId(message_id))
+        .caption(stream.to_captions(None))
+        .parse_mode(tg_type::ParseMode::Html)
+        .await;
+    match result {
+        // Nothing changed since the last update
+        Err(teloxide::RequestError::Api(teloxide::ApiError::MessageNotModified)) => Ok(()),
+        result => result.map(|_| ()).map_err(Into::into),
+    }
+}
+
+#[cfg(test)]
+fn test_stream() -> LiveStream {
+    LiveStream {
+        platform: LivePlatform::Twitch,
+        room_id: "alice".to_string(),
+        streamer: "Alice".to_string(),
+        streamer_url: "https://www.twitch.tv/alice".to_string(),
+        title: "A".to_string(),
+        url: "https://www.twitch.tv/alice".to_string(),
+        living: true,
+        online: Some(100),
+        area: Some("Just Chatting".to_string()),
+        cover: "https://example.com/cover.jpg".to_string(),
+        keyframe: None,
+        start_time: None,
+    }
+}
+
+#[test]
+fn test_live_session() {
+    let mut stream = test_stream();
+    let mut session = LiveSession {
+        start: 1000,
+        ..Default::default()
+    };
+    session.update(&stream);
+    stream.online = Some(300);
+    session.update(&stream);
+    stream.online = Some(200);
+    stream.title = "<B>".to_string();
+    session.update(&stream);
+    session.update(&stream);
+
+    assert_eq!(session.peak, 300);
+    assert_eq!(session.titles, ["A", "<B>"]);
+    assert_eq!(
+        session.summary(1000 + 3600 + 62),
+        "本次直播时长：1:01:02\n最高人气：300\n标题变更：A → &lt;B&gt;"
+    );
+
+    // The same stream continues the session, and a new stream starts a new one
+    stream.start_time = Some(1000);
+    let session = LiveSession::continue_with(Some(session), &stream);
+    assert_eq!(session.peak, 300);
+    stream.start_time = Some(9000);
+    let session = LiveSession::continue_with(Some(session), &stream);
+    assert_eq!(session.start, 9000);
+    assert_eq!(session.peak, 200);
+    assert_eq!(session.titles, ["<B>"]);
+}
+
+#[test]
+fn test_live_captions() {
+    let mut stream = test_stream();
+    assert_eq!(
+        stream.to_captions(None),
+        "<a href=\"https://www.twitch.tv/alice\">Alice</a> 开播了！已有 100 人正在观看\n\
+        直播: <a href=\"https://www.twitch.tv/alice\">A</a>\n分区: #Just Chatting\n"
+    );
+
+    stream.online = None;
+    stream.area = None;
+    assert_eq!(
+        stream.to_captions(None),
+        "<a href=\"https://www.twitch.tv/alice\">Alice</a> 开播了！\n\
+        直播: <a href=\"https://www.twitch.tv/alice\">A</a>\n"
+    );
+
+    stream.living = false;
+    assert_eq!(stream.to_captions(None), "Alice 已下播");
+}
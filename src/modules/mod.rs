@@ -1,17 +1,28 @@
 // Provider Module
+pub mod admin;
 pub mod archlinux;
 pub mod bilibili;
 pub mod collect;
 pub mod currency;
+pub mod download_queue;
 pub mod ehentai;
+pub mod gallery_dl;
+pub mod github;
 pub mod health;
 pub mod ksyx;
+pub mod live;
 pub mod nsfw;
 pub mod piggy;
 pub mod price;
+pub mod rss;
+pub mod stats;
 pub mod steam;
+pub mod sticker;
+pub mod twitch;
 pub mod video_dl;
+pub mod watch_list;
 pub mod weather;
+pub mod youtube;
 pub mod ytd;
 
 // Every module should provide a function that turn user input to [`Sendable`]
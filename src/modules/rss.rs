@@ -0,0 +1,479 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::watch_list::{Update, WatchList};
+use crate::http::HttpClient;
+use crate::{app::AppData, event::EventWatcher, helper::Html};
+use redis::Commands;
+use reqwest::{header, StatusCode};
+use serde::Deserialize;
+use teloxide::utils::html::escape;
+
+/// Name of the watcher, which is also the event name of the subscribe registry
+pub const RSS_WATCHER: &str = "RssFeedWatcher";
+/// Feeds subscribed by the chats, keyed by the URL
+const FEEDS: WatchList = WatchList {
+    watcher: RSS_WATCHER,
+    key_prefix: "RSS",
+};
+
+lazy_static::lazy_static!(
+    static ref FEED_CLIENT: HttpClient = feed_client();
+);
+
+/// Feed content shared by RSS 2.0 and Atom
+#[derive(Debug, PartialEq)]
+pub struct Feed {
+    pub title: String,
+    pub items: Vec<FeedItem>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct FeedItem {
+    /// Unique id of the item, fallback to the link or the title when the feed doesn't provide one
+    pub guid: String,
+    pub title: String,
+    pub link: Option<String>,
+}
+
+impl FeedItem {
+    fn to_message(&self, feed_title: &str) -> String {
+        let title = escape(&self.title);
+        let title = match &self.link {
+            Some(link) => Html::a(&escape(link), &title),
+            None => title,
+        };
+        format!("{}\n{title}", Html::b(escape(feed_title)))
+    }
+}
+
+/// Element text that might come with attributes, like `<guid isPermaLink="false">`
+#[derive(Deserialize, Debug, Default)]
+struct Text {
+    #[serde(rename = "$text", default)]
+    value: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Rss {
+    channel: RssChannel,
+}
+
+#[derive(Deserialize, Debug)]
+struct RssChannel {
+    #[serde(default)]
+    title: Text,
+    #[serde(rename = "item", default)]
+    items: Vec<RssItem>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RssItem {
+    title: Option<Text>,
+    link: Option<Text>,
+    guid: Option<Text>,
+}
+
+#[derive(Deserialize, Debug)]
+struct AtomFeed {
+    #[serde(default)]
+    title: Text,
+    #[serde(rename = "entry", default)]
+    entries: Vec<AtomEntry>,
+}
+
+#[derive(Deserialize, Debug)]
+struct AtomEntry {
+    id: Option<Text>,
+    title: Option<Text>,
+    #[serde(rename = "link", default)]
+    links: Vec<AtomLink>,
+}
+
+#[derive(Deserialize, Debug)]
+struct AtomLink {
+    #[serde(rename = "@href")]
+    href: String,
+    #[serde(rename = "@rel")]
+    rel: Option<String>,
+}
+
+fn non_empty(text: Option<Text>) -> Option<String> {
+    text.map(|text| text.value.trim().to_string())
+        .filter(|text| !text.is_empty())
+}
+
+impl Feed {
+    fn updates(&self) -> Vec<Update> {
+        self.items
+            .iter()
+            .map(|item| Update {
+                id: item.guid.clone(),
+                message: item.to_message(&self.title),
+            })
+            .collect()
+    }
+
+    /// Parse the RSS 2.0 or Atom document
+    pub fn parse(xml: &str) -> anyhow::Result<Self> {
+        let rss_err = match quick_xml::de::from_str::<Rss>(xml) {
+            Ok(rss) => return Ok(Self::from_rss(rss)),
+            Err(err) => err,
+        };
+        match quick_xml::de::from_str::<AtomFeed>(xml) {
+            Ok(atom) if xml.contains("<feed") => Ok(Self::from_atom(atom)),
+            Ok(_) => anyhow::bail!("not a RSS or Atom feed"),
+            Err(atom_err) => {
+                anyhow::bail!("not a RSS or Atom feed (RSS: {rss_err}, Atom: {atom_err})")
+            }
+        }
+    }
+
+    fn from_rss(rss: Rss) -> Self {
+        let items = rss
+            .channel
+            .items
+            .into_iter()
+            .filter_map(|item| {
+                let title = non_empty(item.title);
+                let link = non_empty(item.link);
+                let guid = non_empty(item.guid)
+                    .or_else(|| link.clone())
+                    .or_else(|| title.clone())?;
+                Some(FeedItem {
+                    guid,
+                    title: title.unwrap_or_else(|| "(untitled)".to_string()),
+                    link,
+                })
+            })
+            .collect();
+        Self {
+            title: rss.channel.title.value.trim().to_string(),
+            items,
+        }
+    }
+
+    fn from_atom(atom: AtomFeed) -> Self {
+        let items = atom
+            .entries
+            .into_iter()
+            .filter_map(|entry| {
+                let title = non_empty(entry.title);
+                // The link without rel is the alternate link as well
+                let link = entry
+                    .links
+                    .into_iter()
+                    .find(|link| link.rel.as_deref().unwrap_or("alternate") == "alternate")
+                    .map(|link| link.href);
+                let guid = non_empty(entry.id)
+                    .or_else(|| link.clone())
+                    .or_else(|| title.clone())?;
+                Some(FeedItem {
+                    guid,
+                    title: title.unwrap_or_else(|| "(untitled)".to_string()),
+                    link,
+                })
+            })
+            .collect();
+        Self {
+            title: atom.title.value.trim().to_string(),
+            items,
+        }
+    }
+}
+
+/// Check that the feed URL is a HTTP(S) URL of a public host, so that the bot can't be used to
+/// reach the services in its own network
+pub async fn check_feed_url(url: &str) -> anyhow::Result<()> {
+    let url = reqwest::Url::parse(url)?;
+    check_feed_target(&url)?;
+    let host = feed_host(&url)?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    for addr in tokio::net::lookup_host((host, port)).await? {
+        anyhow::ensure!(is_public_ip(addr.ip()), "{host} is not a public host");
+    }
+    Ok(())
+}
+
+/// Check the scheme and the IP address host of the URL. The domain hosts are checked by
+/// [`PublicResolver`] when connecting.
+fn check_feed_target(url: &reqwest::Url) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        matches!(url.scheme(), "http" | "https"),
+        "only HTTP and HTTPS feeds are supported"
+    );
+    let host = feed_host(url)?;
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        anyhow::ensure!(is_public_ip(ip), "{host} is not a public host");
+    }
+    Ok(())
+}
+
+fn feed_host(url: &reqwest::Url) -> anyhow::Result<&str> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow::anyhow!("missing host"))?;
+    // IPv6 hosts are wrapped in brackets in the URL
+    Ok(host.trim_start_matches('[').trim_end_matches(']'))
+}
+
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            // 100.64.0.0/10 is shared by the carrier-grade NAT
+            let shared = first == 100 && second & 0xc0 == 64;
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || shared)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ip(IpAddr::V4(ip)),
+            None => {
+                let [first, second, ..] = ip.segments();
+                // 2001:db8::/32 is reserved for the documentation
+                let documentation = first == 0x2001 && second == 0xdb8;
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local()
+                    || ip.is_multicast()
+                    || documentation)
+            }
+        },
+    }
+}
+
+/// Resolve the feed hosts and refuse to connect to the addresses that are not public. It runs on
+/// every connection, so a domain can't be changed to point to a private address later.
+struct PublicResolver;
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> =
+                tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            if addrs.iter().any(|addr| !is_public_ip(addr.ip())) {
+                return Err(format!("{} is not a public host", name.as_str()).into());
+            }
+            let addrs: reqwest::dns::Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+/// Client for fetching the feeds, which only connects to the public hosts, including the hosts
+/// redirected to
+fn feed_client() -> HttpClient {
+    const MAX_REDIRECTS: usize = 5;
+    let redirect = reqwest::redirect::Policy::custom(|attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            return attempt.error("too many redirects");
+        }
+        match check_feed_target(attempt.url()) {
+            Ok(()) => attempt.follow(),
+            Err(err) => attempt.error(err),
+        }
+    });
+    let client = reqwest::Client::builder()
+        .dns_resolver(Arc::new(PublicResolver))
+        .redirect(redirect)
+        .timeout(Duration::from_secs(30))
+        .build()
+        .expect("fail to build the feed client");
+    HttpClient(client)
+}
+
+/// Fetch the feed, return `None` if it is not modified since the last fetch
+pub async fn fetch_feed(data: &AppData, url: &str) -> anyhow::Result<Option<Feed>> {
+    check_feed_target(&reqwest::Url::parse(url)?)?;
+    let cache_key = FEEDS.http_cache_key(url);
+    let (etag, last_modified): (Option<String>, Option<String>) = data
+        .cacher
+        .get_conn()
+        .hget(&cache_key, &["etag", "last_modified"])?;
+
+    let mut request = FEED_CLIENT.get(url);
+    if let Some(etag) = etag {
+        request = request.header(header::IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = last_modified {
+        request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+    }
+    let response = request.send().await?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
+    let response = response.error_for_status()?;
+
+    let validator = |name: header::HeaderName| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from)
+    };
+    let validators = [
+        ("etag", validator(header::ETAG)),
+        ("last_modified", validator(header::LAST_MODIFIED)),
+    ];
+    let feed = Feed::parse(&response.text().await?)?;
+
+    let mut conn = data.cacher.get_conn();
+    let () = conn.del(&cache_key)?;
+    for (field, value) in validators {
+        if let Some(value) = value {
+            let () = conn.hset(&cache_key, field, value)?;
+        }
+    }
+    Ok(Some(feed))
+}
+
+/// Subscribe the feed for the chat after checking that it is a valid feed. Return the feed title,
+/// or `None` if the feed is already watched for other chats.
+pub async fn subscribe_feed(
+    data: &AppData,
+    chat_id: i64,
+    url: &str,
+) -> anyhow::Result<Option<String>> {
+    FEEDS
+        .watch(data, chat_id, url, async {
+            let feed = fetch_feed(data, url)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("unexpected not modified response"))?;
+            let updates = feed.updates();
+            Ok((feed.title, updates))
+        })
+        .await
+}
+
+/// Feeds subscribed by the chat in a stable order, which is used as the id for `/rss del`
+pub fn list_feeds(data: &AppData, chat_id: i64) -> anyhow::Result<Vec<String>> {
+    FEEDS.list(data, chat_id)
+}
+
+pub fn unsubscribe_feed(data: &AppData, chat_id: i64, url: &str) -> anyhow::Result<()> {
+    FEEDS.unwatch(data, chat_id, url)?;
+    Ok(())
+}
+
+pub fn spawn_rss_listener(bot: teloxide::Bot, data: AppData) {
+    EventWatcher::builder()
+        .name(RSS_WATCHER)
+        .bot(bot)
+        .data(data)
+        .client(None)
+        .heartbeat_interval(600) // 10mins
+        .build()
+        .start_with_task(watch_and_response);
+}
+
+async fn watch_and_response(ctx: EventWatcher<()>) -> anyhow::Result<()> {
+    let data = &ctx.data;
+    FEEDS
+        .poll(&ctx, |url| async move {
+            let feed = fetch_feed(data, &url).await?;
+            Ok(feed.map(|feed| feed.updates()))
+        })
+        .await
+}
+
+#[test]
+fn test_parse_rss() {
+    let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0" xmlns:atom="http://www.w3.org/2005/Atom">
+  <channel>
+    <title><![CDATA[ Arch Linux: Recent news updates ]]></title>
+    <link>https://archlinux.org/news/</link>
+    <atom:link href="https://archlinux.org/feeds/news/" rel="self"/>
+    <item>
+      <title>Second &amp; newer</title>
+      <link>https://archlinux.org/news/2/</link>
+      <guid isPermaLink="false">tag:archlinux.org,2024:/news/2/</guid>
+    </item>
+    <item>
+      <title>First</title>
+      <link>https://archlinux.org/news/1/</link>
+    </item>
+  </channel>
+</rss>"#;
+    let feed = Feed::parse(xml).unwrap();
+    assert_eq!(feed.title, "Arch Linux: Recent news updates");
+    assert_eq!(
+        feed.items,
+        [
+            FeedItem {
+                guid: "tag:archlinux.org,2024:/news/2/".to_string(),
+                title: "Second & newer".to_string(),
+                link: Some("https://archlinux.org/news/2/".to_string()),
+            },
+            FeedItem {
+                guid: "https://archlinux.org/news/1/".to_string(),
+                title: "First".to_string(),
+                link: Some("https://archlinux.org/news/1/".to_string()),
+            },
+        ]
+    );
+    assert_eq!(
+        feed.items[0].to_message(&feed.title),
+        "<b>Arch Linux: Recent news updates</b>\n\
+        <a href=\"https://archlinux.org/news/2/\">Second &amp; newer</a>"
+    );
+}
+
+#[test]
+fn test_parse_atom() {
+    let xml = r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title type="text">Release notes</title>
+  <link href="https://example.com/feed.atom" rel="self"/>
+  <entry>
+    <id>urn:uuid:1</id>
+    <title type="html">v1.0</title>
+    <link rel="replies" href="https://example.com/v1.0#comments"/>
+    <link href="https://example.com/v1.0"/>
+  </entry>
+  <entry>
+    <title>No id</title>
+    <link rel="alternate" href="https://example.com/no-id"/>
+  </entry>
+</feed>"#;
+    let feed = Feed::parse(xml).unwrap();
+    assert_eq!(feed.title, "Release notes");
+    assert_eq!(feed.items.len(), 2);
+    assert_eq!(feed.items[0].guid, "urn:uuid:1");
+    assert_eq!(
+        feed.items[0].link.as_deref(),
+        Some("https://example.com/v1.0")
+    );
+    assert_eq!(feed.items[1].guid, "https://example.com/no-id");
+
+    assert!(Feed::parse("<html><body>Not a feed</body></html>").is_err());
+}
+
+#[tokio::test]
+async fn test_check_feed_url() {
+    assert!(check_feed_url("https://1.1.1.1/feed.xml").await.is_ok());
+    assert!(check_feed_url("ftp://1.1.1.1/feed.xml").await.is_err());
+    assert!(check_feed_url("file:///etc/passwd").await.is_err());
+    assert!(check_feed_url("http://127.0.0.1:6379/").await.is_err());
+    assert!(check_feed_url("http://192.168.1.1/feed").await.is_err());
+    assert!(check_feed_url("http://169.254.169.254/latest")
+        .await
+        .is_err());
+    assert!(check_feed_url("http://[::1]/feed").await.is_err());
+    assert!(check_feed_url("http://[::ffff:10.0.0.1]/feed")
+        .await
+        .is_err());
+    assert!(check_feed_url("http://[fd00::1]/feed").await.is_err());
+    assert!(check_feed_url("http://100.64.0.1/feed").await.is_err());
+    assert!(check_feed_url("http://[2001:db8::1]/feed").await.is_err());
+    assert!(check_feed_url("http://[ff02::1]/feed").await.is_err());
+    assert!(check_feed_url("http://100.128.0.1/feed").await.is_ok());
+}
@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+
+use redis::Commands;
+use teloxide::types::User;
+
+use crate::app::AppData;
+
+/// Chats that don't want their command usage recorded
+const OPT_OUT_CHATS: &str = "COMMAND_STATS_OPT_OUT";
+/// Days the daily stats are kept, which covers the longest `/stats` period
+const RETENTION_DAYS: i64 = 31;
+/// Number of commands and users shown in `/stats`
+const TOP_ENTRIES: usize = 10;
+
+fn commands_key(chat_id: i64, day: &str) -> String {
+    format!("COMMAND_STATS:{chat_id}:{day}")
+}
+
+fn users_key(chat_id: i64, day: &str) -> String {
+    format!("COMMAND_STATS_USERS:{chat_id}:{day}")
+}
+
+fn names_key(chat_id: i64) -> String {
+    format!("COMMAND_STATS_NAMES:{chat_id}")
+}
+
+/// Day of the stats in `YYYYMMDD`, `days_ago` days before today
+fn stats_day(days_ago: i64) -> String {
+    let day = chrono::Local::now().date_naive() - chrono::Duration::days(days_ago);
+    day.format("%Y%m%d").to_string()
+}
+
+pub fn is_opted_out(data: &AppData, chat_id: i64) -> anyhow::Result<bool> {
+    let opted_out = data.cacher.get_conn().sismember(OPT_OUT_CHATS, chat_id)?;
+    Ok(opted_out)
+}
+
+/// Stop or restart recording the command usage of the chat. The recorded stats are deleted when
+/// the chat opts out.
+pub fn set_opt_out(data: &AppData, chat_id: i64, opt_out: bool) -> anyhow::Result<()> {
+    let mut conn = data.cacher.get_conn();
+    if !opt_out {
+        let () = conn.srem(OPT_OUT_CHATS, chat_id)?;
+        return Ok(());
+    }
+
+    let () = conn.sadd(OPT_OUT_CHATS, chat_id)?;
+    let mut keys: Vec<String> = (0..RETENTION_DAYS)
+        .map(stats_day)
+        .flat_map(|day| [commands_key(chat_id, &day), users_key(chat_id, &day)])
+        .collect();
+    keys.push(names_key(chat_id));
+    let () = conn.del(keys)?;
+    Ok(())
+}
+
+/// Count a command invocation for the chat and the user, unless the chat opted out
+pub fn record_command(
+    data: &AppData,
+    chat_id: i64,
+    user: Option<&User>,
+    command: &str,
+) -> anyhow::Result<()> {
+    if is_opted_out(data, chat_id)? {
+        return Ok(());
+    }
+
+    let day = stats_day(0);
+    let ttl = RETENTION_DAYS * 24 * 60 * 60;
+    let mut pipe = redis::pipe();
+    let commands = commands_key(chat_id, &day);
+    pipe.hincr(&commands, command, 1)
+        .ignore()
+        .expire(&commands, ttl)
+        .ignore();
+    if let Some(user) = user {
+        let users = users_key(chat_id, &day);
+        let names = names_key(chat_id);
+        pipe.hincr(&users, user.id.0, 1)
+            .ignore()
+            .expire(&users, ttl)
+            .ignore()
+            .hset(&names, user.id.0, user.full_name())
+            .ignore()
+            .expire(&names, ttl)
+            .ignore();
+    }
+    let () = pipe.query(&mut *data.cacher.get_conn())?;
+    Ok(())
+}
+
+/// Most used commands and most active users of the chat in the recent days
+#[derive(Debug, PartialEq)]
+pub struct ChatStats {
+    pub days: i64,
+    pub commands: Vec<(String, u64)>,
+    pub users: Vec<(String, u64)>,
+}
+
+impl ChatStats {
+    pub fn collect(data: &AppData, chat_id: i64, days: i64) -> anyhow::Result<Self> {
+        let mut conn = data.cacher.get_conn();
+        let mut commands: HashMap<String, u64> = HashMap::new();
+        let mut users: HashMap<String, u64> = HashMap::new();
+        for day in (0..days).map(stats_day) {
+            let daily: HashMap<String, u64> = conn.hgetall(commands_key(chat_id, &day))?;
+            merge_counts(&mut commands, daily);
+            let daily: HashMap<String, u64> = conn.hgetall(users_key(chat_id, &day))?;
+            merge_counts(&mut users, daily);
+        }
+
+        let names: HashMap<String, String> = conn.hgetall(names_key(chat_id))?;
+        let users = top_entries(users)
+            .into_iter()
+            .map(|(id, count)| (names.get(&id).cloned().unwrap_or(id), count))
+            .collect();
+        Ok(Self {
+            days,
+            commands: top_entries(commands),
+            users,
+        })
+    }
+
+    pub fn to_message(&self) -> String {
+        if self.commands.is_empty() {
+            return format!("No command used in the last {} days", self.days);
+        }
+
+        let rank = |entries: &[(String, u64)]| {
+            entries
+                .iter()
+                .enumerate()
+                .map(|(i, (name, count))| format!("{}. {name}: {count}", i + 1))
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        let mut message = format!(
+            "Stats of the last {} days\n\nMost used commands:\n{}",
+            self.days,
+            rank(&self.commands)
+        );
+        if !self.users.is_empty() {
+            message.push_str(&format!("\n\nMost active users:\n{}", rank(&self.users)));
+        }
+        message
+    }
+}
+
+fn merge_counts(total: &mut HashMap<String, u64>, counts: HashMap<String, u64>) {
+    for (key, count) in counts {
+        *total.entry(key).or_default() += count;
+    }
+}
+
+/// Entries with the most counts, the ties are sorted by name
+fn top_entries(counts: HashMap<String, u64>) -> Vec<(String, u64)> {
+    let mut entries: Vec<_> = counts.into_iter().collect();
+    entries.sort_by(|(a_name, a_count), (b_name, b_count)| {
+        b_count.cmp(a_count).then_with(|| a_name.cmp(b_name))
+    });
+    entries.truncate(TOP_ENTRIES);
+    entries
+}
+
+/// Parse the `/stats` period like `7d` into days
+pub fn parse_period(period: &str) -> Option<i64> {
+    match period {
+        "7d" => Some(7),
+        "30d" => Some(30),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_chat_stats() {
+    let mut commands = HashMap::from([("ytdlp".to_string(), 3), ("tr".to_string(), 1)]);
+    merge_counts(
+        &mut commands,
+        HashMap::from([("tr".to_string(), 2), ("roll".to_string(), 5)]),
+    );
+    let commands = top_entries(commands);
+    assert_eq!(
+        commands,
+        [
+            ("roll".to_string(), 5),
+            ("tr".to_string(), 3),
+            ("ytdlp".to_string(), 3)
+        ]
+    );
+
+    let stats = ChatStats {
+        days: 7,
+        commands,
+        users: vec![("Alice".to_string(), 11)],
+    };
+    assert_eq!(
+        stats.to_message(),
+        "Stats of the last 7 days\n\n\
+        Most used commands:\n1. roll: 5\n2. tr: 3\n3. ytdlp: 3\n\n\
+        Most active users:\n1. Alice: 11"
+    );
+
+    assert_eq!(parse_period("30d"), Some(30));
+    assert_eq!(parse_period("1y"), None);
+}
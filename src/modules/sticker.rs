@@ -0,0 +1,160 @@
+use anyhow::Context;
+use image::{imageops, DynamicImage, ImageFormat, RgbaImage};
+use std::path::Path;
+use std::process::Stdio;
+use tokio::process;
+
+/// Telegram requires a static sticker to fit in a 512x512 box with at least one side being exactly
+/// 512px.
+pub const STICKER_SIZE: u32 = 512;
+
+/// Video stickers must not be longer than 3 seconds.
+pub const VIDEO_STICKER_MAX_SECONDS: u32 = 3;
+
+/// Video stickers must not be larger than 256 KB.
+pub const VIDEO_STICKER_MAX_BYTES: usize = 256 * 1024;
+
+/// Bitrates to try in order when encoding the video sticker. The first one is good enough for most
+/// of the short animations, the rest are used when the output exceeds the size limit.
+const VIDEO_STICKER_BITRATES: [&str; 4] = ["600K", "400K", "250K", "150K"];
+
+/// Scale the given image to fit into a 512x512 box and center it on a transparent canvas of the
+/// same size, so that the result is always a legal static sticker whatever the input ratio is.
+pub fn pad_to_sticker(image: &DynamicImage) -> RgbaImage {
+    let resized = image
+        .resize(STICKER_SIZE, STICKER_SIZE, imageops::FilterType::Lanczos3)
+        .into_rgba8();
+
+    let mut canvas = RgbaImage::new(STICKER_SIZE, STICKER_SIZE);
+    let x = (STICKER_SIZE - resized.width()) / 2;
+    let y = (STICKER_SIZE - resized.height()) / 2;
+    imageops::overlay(&mut canvas, &resized, x.into(), y.into());
+
+    canvas
+}
+
+/// Decode an image of any format supported by the `image` crate, and convert it into a WebP encoded
+/// static sticker.
+pub fn legalize_static_sticker(raw: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let image = image::load_from_memory(raw)?;
+    let sticker = pad_to_sticker(&image);
+
+    let mut buffer = std::io::Cursor::new(Vec::new());
+    // The image crate only provides lossless WebP encoder, which is fine for a 512px image.
+    sticker.write_to(&mut buffer, ImageFormat::WebP)?;
+
+    Ok(buffer.into_inner())
+}
+
+/// Build the ffmpeg arguments to convert the `input` video into a 512px VP9 WebM video sticker with
+/// the given bitrate. Audio is dropped, and the video is trimmed to 3 seconds at no more than 30 FPS.
+pub fn video_sticker_ffmpeg_args(input: &Path, output: &Path, bitrate: &str) -> Vec<String> {
+    let scale = format!(
+        "scale={STICKER_SIZE}:{STICKER_SIZE}:force_original_aspect_ratio=decrease,fps=fps='min(30,source_fps)'"
+    );
+    [
+        "-y",
+        "-i",
+        input.to_str().expect("sticker input path must be UTF-8"),
+        "-t",
+        &VIDEO_STICKER_MAX_SECONDS.to_string(),
+        "-an",
+        "-vf",
+        &scale,
+        "-c:v",
+        "libvpx-vp9",
+        "-pix_fmt",
+        "yuva420p",
+        "-b:v",
+        bitrate,
+        "-f",
+        "webm",
+        output.to_str().expect("sticker output path must be UTF-8"),
+    ]
+    .iter()
+    .map(|arg| arg.to_string())
+    .collect()
+}
+
+/// Convert GIF, animation or any video that ffmpeg can decode into a WebM encoded video sticker.
+/// The bitrate is lowered step by step until the result fit into the 256 KB limit.
+pub async fn legalize_video_sticker(raw: &[u8]) -> anyhow::Result<Vec<u8>> {
+    use which::which;
+    let ffmpeg = which("ffmpeg").with_context(|| "can not found ffmpeg program")?;
+
+    let workdir = tempfile::tempdir().with_context(|| "fail to create temp dir for ffmpeg")?;
+    let input = workdir.path().join("input");
+    let output = workdir.path().join("sticker.webm");
+    tokio::fs::write(&input, raw)
+        .await
+        .with_context(|| "fail to write sticker source into temp dir")?;
+
+    for bitrate in VIDEO_STICKER_BITRATES {
+        let result = process::Command::new(&ffmpeg)
+            .args(video_sticker_ffmpeg_args(&input, &output, bitrate))
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .output()
+            .await?;
+        if !result.status.success() {
+            anyhow::bail!(
+                "fail to convert video into sticker: {}",
+                String::from_utf8_lossy(&result.stderr)
+            );
+        }
+
+        let webm = tokio::fs::read(&output).await?;
+        if webm.len() <= VIDEO_STICKER_MAX_BYTES {
+            return Ok(webm);
+        }
+    }
+
+    anyhow::bail!("This video is too complex to fit into the 256 KB video sticker limit")
+}
+
+#[test]
+fn test_pad_to_sticker() {
+    let wide = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+        1024,
+        256,
+        image::Rgb([255, 0, 0]),
+    ));
+    let sticker = pad_to_sticker(&wide);
+    assert_eq!(sticker.dimensions(), (STICKER_SIZE, STICKER_SIZE));
+    // 1024x256 is scaled into 512x128, so the top and bottom 192px are transparent padding
+    assert_eq!(sticker.get_pixel(0, 0).0[3], 0);
+    assert_eq!(sticker.get_pixel(256, 256).0, [255, 0, 0, 255]);
+
+    let tiny = DynamicImage::ImageRgb8(image::RgbImage::new(16, 32));
+    let sticker = pad_to_sticker(&tiny);
+    assert_eq!(sticker.dimensions(), (STICKER_SIZE, STICKER_SIZE));
+    assert_eq!(sticker.get_pixel(0, 256).0[3], 0);
+    assert_eq!(sticker.get_pixel(256, 0).0[3], 255);
+}
+
+#[test]
+fn test_legalize_static_sticker() {
+    let mut png = std::io::Cursor::new(Vec::new());
+    DynamicImage::ImageRgb8(image::RgbImage::new(300, 200))
+        .write_to(&mut png, ImageFormat::Png)
+        .unwrap();
+
+    let webp = legalize_static_sticker(png.get_ref()).unwrap();
+    assert_eq!(image::guess_format(&webp).unwrap(), ImageFormat::WebP);
+    let decoded = image::load_from_memory(&webp).unwrap();
+    assert_eq!(decoded.width(), STICKER_SIZE);
+    assert_eq!(decoded.height(), STICKER_SIZE);
+}
+
+#[test]
+fn test_video_sticker_ffmpeg_args() {
+    let args = video_sticker_ffmpeg_args(Path::new("/tmp/in"), Path::new("/tmp/out.webm"), "400K");
+    let position = |arg: &str| args.iter().position(|a| a == arg).unwrap();
+
+    assert_eq!(args[position("-i") + 1], "/tmp/in");
+    assert_eq!(args[position("-t") + 1], "3");
+    assert_eq!(args[position("-c:v") + 1], "libvpx-vp9");
+    assert_eq!(args[position("-b:v") + 1], "400K");
+    assert!(args.contains(&"-an".to_string()));
+    assert_eq!(args.last().unwrap(), "/tmp/out.webm");
+}
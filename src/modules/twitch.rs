@@ -0,0 +1,245 @@
+use crate::http::HttpClient;
+use crate::{
+    app::AppData,
+    config::{Config, TwitchConfig},
+    event::EventWatcher,
+};
+use redis::Commands;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use super::live::{self, LivePlatform, LiveStream};
+
+pub struct TwitchApi;
+impl TwitchApi {
+    const OAUTH_TOKEN: &'static str = "https://id.twitch.tv/oauth2/token";
+    const STREAMS: &'static str = "https://api.twitch.tv/helix/streams";
+    /// Helix accepts at most 100 users in one request
+    const MAX_USERS_PER_REQUEST: usize = 100;
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct StreamsResponse {
+    data: Vec<Stream>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Stream {
+    pub user_login: String,
+    pub user_name: String,
+    pub game_name: String,
+    pub title: String,
+    pub viewer_count: u64,
+    pub started_at: String,
+    /// Thumbnail URL template with `{width}` and `{height}` placeholders
+    pub thumbnail_url: String,
+}
+
+impl Stream {
+    fn thumbnail(&self) -> String {
+        self.thumbnail_url
+            .replace("{width}", "1280")
+            .replace("{height}", "720")
+    }
+
+    fn to_live_stream(&self) -> LiveStream {
+        let url = format!("https://www.twitch.tv/{}", self.user_login);
+        LiveStream {
+            platform: LivePlatform::Twitch,
+            room_id: self.user_login.clone(),
+            streamer: self.user_name.clone(),
+            streamer_url: url.clone(),
+            title: self.title.clone(),
+            url,
+            living: true,
+            online: Some(self.viewer_count),
+            area: Some(self.game_name.clone()),
+            cover: self.thumbnail(),
+            keyframe: Some(self.thumbnail()),
+            start_time: chrono::DateTime::parse_from_rfc3339(&self.started_at)
+                .ok()
+                .map(|time| time.timestamp()),
+        }
+    }
+}
+
+/// Stream of the user that is not living
+fn offline_stream(login: &str) -> LiveStream {
+    let url = format!("https://www.twitch.tv/{login}");
+    LiveStream {
+        platform: LivePlatform::Twitch,
+        room_id: login.to_string(),
+        streamer: login.to_string(),
+        streamer_url: url.clone(),
+        title: String::new(),
+        url,
+        living: false,
+        online: None,
+        area: None,
+        cover: format!("https://static-cdn.jtvnw.net/previews-ttv/live_user_{login}-1280x720.jpg"),
+        keyframe: None,
+        start_time: None,
+    }
+}
+
+/// Name of the watcher, which is also the event name of the subscribe registry
+pub const TWITCH_WATCHER: &str = "TwitchLiveWatcher";
+
+/// The subscriptions in the config with the logins lowercased, which is how Twitch returns them,
+/// so that a stream is always cached under the same key
+pub fn subscribe_relation(
+    twitch_live_event: &HashMap<String, Vec<String>>,
+) -> HashMap<String, Vec<String>> {
+    twitch_live_event
+        .iter()
+        .map(|(chat, logins)| {
+            let logins = logins.iter().map(|login| login.to_ascii_lowercase());
+            (chat.clone(), logins.collect())
+        })
+        .collect()
+}
+
+/// Always started even if nothing is subscribed, so the subscriptions added by reloading the
+/// config file are picked up
+pub fn spawn_twitch_live_listener(bot: teloxide::Bot, data: AppData, config: &Config) {
+    EventWatcher::builder()
+        .name(TWITCH_WATCHER)
+        .bot(bot)
+        .data(data)
+        .client(None)
+        .heartbeat_interval(120) // 2mins
+        .build()
+        .setup_subscribe_registry(subscribe_relation(&config.twitch_live_event).iter())
+        .start_with_task(watch_and_response);
+}
+
+/// Get the app access token from cache, or request a new one through the client credentials flow
+async fn get_app_token(
+    data: &AppData,
+    client: &HttpClient,
+    twitch: &TwitchConfig,
+) -> anyhow::Result<String> {
+    const KEY: &str = "TWITCH_APP_TOKEN";
+    let cached: Option<String> = data.cacher.get_conn().get(KEY)?;
+    if let Some(token) = cached {
+        return Ok(token);
+    }
+
+    let response: TokenResponse = client
+        .post(TwitchApi::OAUTH_TOKEN)
+        .form(&[
+            ("client_id", twitch.client_id.as_str()),
+            ("client_secret", twitch.client_secret.as_str()),
+            ("grant_type", "client_credentials"),
+        ])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    // Refresh the token a bit earlier before it expires
+    let ttl = response.expires_in.saturating_sub(60).max(1);
+    let () = data
+        .cacher
+        .get_conn()
+        .set_ex(KEY, &response.access_token, ttl)?;
+    Ok(response.access_token)
+}
+
+pub async fn get_streams(
+    client: &HttpClient,
+    twitch: &TwitchConfig,
+    token: &str,
+    logins: &[String],
+) -> anyhow::Result<Vec<Stream>> {
+    let mut streams = Vec::new();
+    for chunk in logins.chunks(TwitchApi::MAX_USERS_PER_REQUEST) {
+        let query: Vec<_> = chunk.iter().map(|login| ("user_login", login)).collect();
+        let response: StreamsResponse = client
+            .get(TwitchApi::STREAMS)
+            .query(&query)
+            .header("Client-Id", &twitch.client_id)
+            .bearer_auth(token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        streams.extend(response.data);
+    }
+    Ok(streams)
+}
+
+async fn watch_and_response(ctx: EventWatcher<()>) -> anyhow::Result<()> {
+    let logins: Vec<String> = ctx.event_pool()?;
+    if logins.is_empty() {
+        return Ok(());
+    }
+    let Some(twitch) = Config::get_global_config().twitch.clone() else {
+        anyhow::bail!("twitch_live_event is set without the [twitch] credentials");
+    };
+    let client = ctx.data.twitch_client();
+
+    let token = get_app_token(&ctx.data, &client, &twitch).await?;
+    let living = match get_streams(&client, &twitch, &token, &logins).await {
+        Ok(living) => living,
+        Err(err) => {
+            // The token might be revoked, request a new one at the next heartbeat
+            let () = ctx.data.cacher.get_conn().del("TWITCH_APP_TOKEN")?;
+            return Err(err);
+        }
+    };
+
+    let streams = logins.into_iter().map(|login| {
+        let stream = living
+            .iter()
+            .find(|stream| stream.user_login.eq_ignore_ascii_case(&login))
+            .map(Stream::to_live_stream)
+            .unwrap_or_else(|| offline_stream(&login.to_ascii_lowercase()));
+        (login, stream)
+    });
+    live::process_live_streams(&ctx, streams).await
+}
+
+#[test]
+fn test_twitch_stream() {
+    let response: StreamsResponse = serde_json::from_value(serde_json::json!({
+        "data": [{
+            "id": "1",
+            "user_login": "alice",
+            "user_name": "Alice",
+            "game_name": "Just Chatting",
+            "type": "live",
+            "title": "Hello",
+            "viewer_count": 42,
+            "started_at": "2024-01-01T00:00:00Z",
+            "thumbnail_url": "https://static-cdn.jtvnw.net/previews-ttv/live_user_alice-{width}x{height}.jpg",
+        }],
+        "pagination": {},
+    }))
+    .unwrap();
+
+    let stream = response.data[0].to_live_stream();
+    assert!(stream.living);
+    assert_eq!(stream.room_id, "alice");
+    assert_eq!(offline_stream("alice").room_id, stream.room_id);
+
+    let relation = subscribe_relation(&HashMap::from([(
+        "-10012345".to_string(),
+        vec!["Alice".to_string()],
+    )]));
+    assert_eq!(relation["-10012345"], ["alice"]);
+    assert_eq!(stream.online, Some(42));
+    assert_eq!(stream.start_time, Some(1704067200));
+    assert_eq!(
+        stream.cover,
+        "https://static-cdn.jtvnw.net/previews-ttv/live_user_alice-1280x720.jpg"
+    );
+    assert_eq!(offline_stream("alice").cover, stream.cover);
+}
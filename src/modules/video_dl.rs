@@ -1,6 +1,241 @@
 use std::future::Future;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use teloxide::types::InputFile;
+use tokio::sync::watch;
+
+use super::gallery_dl::GalleryDlPost;
+use super::ytd::{DownloadMode, DownloadProgress, YtdlpVideo};
+use crate::config::OversizePolicy;
+
+/// How a downloaded file should be sent to Telegram
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaKind {
+    Photo,
+    Video,
+    Audio,
+}
+
+impl MediaKind {
+    /// Guess the media kind from the file extension, return `None` for unsupported files
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "jpg" | "jpeg" | "png" | "webp" => Some(Self::Photo),
+            "mp4" | "webm" | "mkv" | "mov" | "gif" => Some(Self::Video),
+            "mp3" | "m4a" | "ogg" | "opus" | "flac" => Some(Self::Audio),
+            _ => None,
+        }
+    }
+}
+
+/// A file ready to be uploaded, with the optional metadata displayed by Telegram
+#[derive(Debug, Clone)]
+pub struct MediaFile {
+    pub file: InputFile,
+    pub kind: MediaKind,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub duration: Option<u32>,
+    pub thumbnail: Option<InputFile>,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+}
+
+impl MediaFile {
+    pub fn new(file: InputFile, kind: MediaKind) -> Self {
+        Self {
+            file,
+            kind,
+            width: None,
+            height: None,
+            duration: None,
+            thumbnail: None,
+            title: None,
+            performer: None,
+        }
+    }
+}
+
+/// Options for a single download request
+#[derive(Clone)]
+pub struct DownloadOptions {
+    pub mode: DownloadMode,
+    /// 1-based index of the playlist entry to download
+    pub playlist_item: Option<usize>,
+    pub progress: watch::Sender<DownloadProgress>,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            mode: DownloadMode::default(),
+            playlist_item: None,
+            progress: watch::channel(DownloadProgress::default()).0,
+        }
+    }
+}
 
 pub trait VideoDownloader: Send + Sized {
-    fn download_from_url(u: &str) -> impl Future<Output = anyhow::Result<Self>> + Send;
+    fn download_from_url(
+        u: &str,
+        options: DownloadOptions,
+    ) -> impl Future<Output = anyhow::Result<Self>> + Send;
     fn provide_caption(&self) -> String;
+    /// Files to be uploaded in order. More than one file are sent as media groups.
+    fn media_files(&self) -> Vec<MediaFile>;
+
+    /// Make sure the files can be uploaded within the given size limit
+    fn fit_upload_limit(
+        &mut self,
+        _limit_mb: u64,
+        _policy: OversizePolicy,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Whether the download is the first entry of a playlist that user might not want
+    fn maybe_playlist(&self) -> bool {
+        false
+    }
+
+    /// Remove the downloaded files
+    fn clean(self) -> impl Future<Output = anyhow::Result<()>> + Send;
+}
+
+/// Downloader implementations that can be chosen by [`downloaders_for`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Downloader {
+    Ytdlp,
+    GalleryDl,
+}
+
+/// Return the downloaders that should be tried in order for the given URL
+pub fn downloaders_for(url: &str, mode: DownloadMode) -> &'static [Downloader] {
+    if mode == DownloadMode::Audio {
+        return &[Downloader::Ytdlp];
+    }
+
+    let host = reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| {
+            url.host_str()
+                .map(|host| host.trim_start_matches("www.").to_string())
+        })
+        .unwrap_or_default();
+    match host.as_str() {
+        // Tweets with images only are not supported by yt-dlp
+        "twitter.com" | "mobile.twitter.com" | "x.com" => {
+            &[Downloader::Ytdlp, Downloader::GalleryDl]
+        }
+        "pixiv.net" => &[Downloader::GalleryDl],
+        _ => &[Downloader::Ytdlp],
+    }
+}
+
+/// Media downloaded by the downloader chosen from the URL
+pub enum RegistryMedia {
+    Ytdlp(Box<YtdlpVideo>),
+    GalleryDl(GalleryDlPost),
+}
+
+macro_rules! dispatch {
+    ($media:expr, $inner:ident => $call:expr) => {
+        match $media {
+            RegistryMedia::Ytdlp($inner) => $call,
+            RegistryMedia::GalleryDl($inner) => $call,
+        }
+    };
+}
+
+impl VideoDownloader for RegistryMedia {
+    async fn download_from_url(u: &str, options: DownloadOptions) -> anyhow::Result<Self> {
+        let mut errors = Vec::new();
+        for downloader in downloaders_for(u, options.mode) {
+            let result = match downloader {
+                Downloader::Ytdlp => YtdlpVideo::download_from_url(u, options.clone())
+                    .await
+                    .map(|video| Self::Ytdlp(Box::new(video))),
+                Downloader::GalleryDl => GalleryDlPost::download_from_url(u, options.clone())
+                    .await
+                    .map(Self::GalleryDl),
+            };
+            match result {
+                Ok(media) => return Ok(media),
+                Err(err) => errors.push(format!("{downloader:?}: {err}")),
+            }
+        }
+
+        anyhow::bail!("{}", errors.join("\n"))
+    }
+
+    fn provide_caption(&self) -> String {
+        dispatch!(self, media => media.provide_caption())
+    }
+
+    fn media_files(&self) -> Vec<MediaFile> {
+        dispatch!(self, media => media.media_files())
+    }
+
+    async fn fit_upload_limit(
+        &mut self,
+        limit_mb: u64,
+        policy: OversizePolicy,
+    ) -> anyhow::Result<()> {
+        match self {
+            Self::Ytdlp(video) => {
+                VideoDownloader::fit_upload_limit(video.as_mut(), limit_mb, policy).await
+            }
+            Self::GalleryDl(post) => {
+                VideoDownloader::fit_upload_limit(post, limit_mb, policy).await
+            }
+        }
+    }
+
+    fn maybe_playlist(&self) -> bool {
+        dispatch!(self, media => media.maybe_playlist())
+    }
+
+    async fn clean(self) -> anyhow::Result<()> {
+        match self {
+            Self::Ytdlp(video) => VideoDownloader::clean(*video).await,
+            Self::GalleryDl(post) => VideoDownloader::clean(post).await,
+        }
+    }
+}
+
+#[test]
+fn test_downloaders_for() {
+    assert_eq!(
+        downloaders_for("https://www.bilibili.com/video/BV1", DownloadMode::Video),
+        [Downloader::Ytdlp]
+    );
+    assert_eq!(
+        downloaders_for("https://x.com/alice/status/1", DownloadMode::Video),
+        [Downloader::Ytdlp, Downloader::GalleryDl]
+    );
+    assert_eq!(
+        downloaders_for("https://x.com/alice/status/1", DownloadMode::Audio),
+        [Downloader::Ytdlp]
+    );
+    assert_eq!(
+        downloaders_for("https://www.pixiv.net/artworks/1", DownloadMode::Video),
+        [Downloader::GalleryDl]
+    );
+}
+
+#[test]
+fn test_media_kind_from_path() {
+    assert_eq!(
+        MediaKind::from_path(Path::new("a/1.JPG")),
+        Some(MediaKind::Photo)
+    );
+    assert_eq!(
+        MediaKind::from_path(Path::new("a/1.mp4")),
+        Some(MediaKind::Video)
+    );
+    assert_eq!(MediaKind::from_path(Path::new("a/1.json")), None);
+    assert_eq!(MediaKind::from_path(Path::new("a/1")), None);
 }
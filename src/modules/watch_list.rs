@@ -0,0 +1,186 @@
+use std::future::Future;
+
+use crate::{app::AppData, event::EventWatcher, metrics};
+use redis::Commands;
+use teloxide::{payloads::SendMessageSetters, prelude::Requester, types as tg_type};
+
+/// Max number of new updates posted for a source in one poll, the rest are only marked as seen
+const MAX_UPDATES_PER_POLL: usize = 5;
+/// Max number of updates remembered as seen for a source, the sources only list the latest ones
+const MAX_SEEN_UPDATES: isize = 200;
+
+/// An update of the watched source rendered into the message
+#[derive(Debug, PartialEq)]
+pub struct Update {
+    pub id: String,
+    pub message: String,
+}
+
+/// Mark the entries as seen, return the entries that have not been seen before in the given order.
+/// All the entries are considered as seen for the first time, to avoid flooding the chats with the
+/// old posts.
+///
+/// The seen ids are kept in a sorted set scored by the time they were last listed, so only the
+/// latest ones are remembered.
+pub fn filter_unseen<'entry, Entry>(
+    data: &AppData,
+    key: &str,
+    entries: &'entry [Entry],
+    id: impl Fn(&Entry) -> &str,
+) -> anyhow::Result<Vec<&'entry Entry>> {
+    let mut conn = data.cacher.get_conn();
+    let initialized: bool = conn.exists(key)?;
+    if !initialized {
+        // The source might have no entry yet, keep a placeholder to mark it initialized
+        let () = conn.zadd(key, "", "+inf")?;
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let mut unseen = Vec::new();
+    for entry in entries {
+        let added: bool = conn.zadd(key, id(entry), now)?;
+        if added && initialized {
+            unseen.push(entry);
+        }
+    }
+    // Drop the oldest ids, the placeholder always ranks the last. The listed ids share the latest
+    // score, keep all of them so they are not taken as new in the next poll.
+    let keep = MAX_SEEN_UPDATES.max(entries.len() as isize);
+    let () = conn.zremrangebyrank(key, 0, -(keep + 2))?;
+
+    Ok(unseen)
+}
+
+/// Sources like feeds or repositories watched by the chats at runtime, and polled by a watcher
+/// that posts their new updates
+pub struct WatchList {
+    /// Name of the watcher, which is also the event name of the subscribe registry
+    pub watcher: &'static str,
+    /// Prefix of all the redis keys of the sources
+    pub key_prefix: &'static str,
+}
+
+impl WatchList {
+    fn seen_key(&self, source: &str) -> String {
+        format!("{}_SEEN:{source}", self.key_prefix)
+    }
+
+    /// Key to cache the HTTP validators of the source, removed when the source is not watched
+    pub fn http_cache_key(&self, source: &str) -> String {
+        format!("{}_HTTP_CACHE:{source}", self.key_prefix)
+    }
+
+    fn chat_sources_key(&self, chat_id: i64) -> String {
+        format!("{}_CHAT_SOURCES:{chat_id}", self.key_prefix)
+    }
+
+    /// Watch the source for the chat. The `fetch` future is only awaited when the source is not
+    /// watched for other chats yet, its updates are marked as seen and its output is returned.
+    pub async fn watch<T>(
+        &self,
+        data: &AppData,
+        chat_id: i64,
+        source: &str,
+        fetch: impl Future<Output = anyhow::Result<(T, Vec<Update>)>>,
+    ) -> anyhow::Result<Option<T>> {
+        // Fetching the source watched for other chats would consume its updates before the watcher
+        let watched: bool = data.cacher.get_conn().exists(self.seen_key(source))?;
+        let output = if watched {
+            None
+        } else {
+            // Fetch the whole source, as the cached validators might belong to a source unwatched
+            // before
+            let () = data.cacher.get_conn().del(self.http_cache_key(source))?;
+            let (output, updates) = fetch.await?;
+            // Existing updates are not new to the chat
+            filter_unseen(data, &self.seen_key(source), &updates, |update| &update.id)?;
+            Some(output)
+        };
+
+        data.cacher.subscribe(self.watcher, &chat_id, &source)?;
+        let () = data
+            .cacher
+            .get_conn()
+            .sadd(self.chat_sources_key(chat_id), source)?;
+        Ok(output)
+    }
+
+    /// Sources watched by the chat in a stable order
+    pub fn list(&self, data: &AppData, chat_id: i64) -> anyhow::Result<Vec<String>> {
+        let mut sources: Vec<String> = data
+            .cacher
+            .get_conn()
+            .smembers(self.chat_sources_key(chat_id))?;
+        sources.sort();
+        Ok(sources)
+    }
+
+    /// Stop watching the source for the chat, return `false` if it is not watched
+    pub fn unwatch(&self, data: &AppData, chat_id: i64, source: &str) -> anyhow::Result<bool> {
+        let mut conn = data.cacher.get_conn();
+        let removed: bool = conn.srem(self.chat_sources_key(chat_id), source)?;
+        if !removed {
+            return Ok(false);
+        }
+        let still_watched = data.cacher.unsubscribe(self.watcher, &chat_id, &source)?;
+        if !still_watched {
+            let () = conn.del(&[self.seen_key(source), self.http_cache_key(source)])?;
+        }
+        Ok(true)
+    }
+
+    /// Poll every watched source, and post the new updates to the chats watching it. `fetch`
+    /// returns the updates from newest to oldest, or `None` if nothing changed since the last poll.
+    pub async fn poll<Fetch, Fut>(&self, ctx: &EventWatcher<()>, fetch: Fetch) -> anyhow::Result<()>
+    where
+        Fetch: Fn(String) -> Fut,
+        Fut: Future<Output = anyhow::Result<Option<Vec<Update>>>>,
+    {
+        let sources: Vec<String> = ctx.event_pool()?;
+        for source in sources {
+            let updates = match fetch(source.clone()).await {
+                Ok(Some(updates)) => updates,
+                Ok(None) => continue,
+                Err(err) => {
+                    metrics::record_upstream_error(&err);
+                    tracing::error!("[{}] fail to fetch {source}: {err}", self.watcher);
+                    continue;
+                }
+            };
+            let key = self.seen_key(&source);
+            let mut unseen = match filter_unseen(&ctx.data, &key, &updates, |update| &update.id) {
+                Ok(unseen) => unseen,
+                Err(err) => {
+                    tracing::error!(
+                        "[{}] fail to filter seen updates of {source}: {err}",
+                        self.watcher
+                    );
+                    continue;
+                }
+            };
+            if unseen.is_empty() {
+                continue;
+            }
+            // Post in the order from oldest to newest
+            unseen.reverse();
+
+            let skipped = unseen.len().saturating_sub(MAX_UPDATES_PER_POLL);
+            let subscribers: Vec<i64> = ctx.get_subscribers(&source)?;
+            for chat_id in subscribers {
+                for update in &unseen[skipped..] {
+                    let result = ctx
+                        .bot
+                        .send_message(tg_type::ChatId(chat_id), &update.message)
+                        .parse_mode(tg_type::ParseMode::Html)
+                        .await;
+                    if let Err(err) = result {
+                        tracing::error!("[{}] fail to notify chat {chat_id}: {err}", self.watcher);
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
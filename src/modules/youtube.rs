@@ -0,0 +1,138 @@
+use crate::http::HttpClient;
+use crate::{app::AppData, config::Config, event::EventWatcher, metrics};
+use scraper::{Html, Selector};
+
+use super::bilibili::BROWSER_USER_AGENT;
+use super::live::{self, LivePlatform, LiveStream};
+
+lazy_static::lazy_static!(
+    static ref MATCH_START_TIMESTAMP: regex::Regex =
+        regex::Regex::new(r#""startTimestamp":"([^"]+)""#).unwrap();
+);
+
+/// Name of the watcher, which is also the event name of the subscribe registry
+pub const YOUTUBE_WATCHER: &str = "YoutubeLiveWatcher";
+
+/// Always started even if nothing is subscribed, so the subscriptions added by reloading the
+/// config file are picked up
+pub fn spawn_youtube_live_listener(bot: teloxide::Bot, data: AppData, config: &Config) {
+    EventWatcher::builder()
+        .name(YOUTUBE_WATCHER)
+        .bot(bot)
+        .data(data)
+        .client(None)
+        .heartbeat_interval(180) // 3mins
+        .build()
+        .setup_subscribe_registry(config.youtube_live_event.iter())
+        .start_with_task(watch_and_response);
+}
+
+/// YouTube has no public API for live status without a key, so read it from the `/live` page of
+/// the channel, which is redirected to the current live stream if there is one.
+pub async fn get_channel_live(client: &HttpClient, channel_id: &str) -> anyhow::Result<LiveStream> {
+    let html = client
+        .get(format!("https://www.youtube.com/channel/{channel_id}/live"))
+        .header(reqwest::header::USER_AGENT, BROWSER_USER_AGENT)
+        // Skip the cookie consent page in EU regions
+        .header(reqwest::header::COOKIE, "CONSENT=YES+1")
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    Ok(parse_live_page(channel_id, &html))
+}
+
+fn parse_live_page(channel_id: &str, html: &str) -> LiveStream {
+    let document = Html::parse_document(html);
+    let select = |selector: &str, attr: &str| {
+        let selector = Selector::parse(selector).unwrap();
+        document
+            .select(&selector)
+            .next()
+            .and_then(|element| element.value().attr(attr))
+            .map(String::from)
+    };
+
+    let channel_url = format!("https://www.youtube.com/channel/{channel_id}");
+    let canonical = select("link[rel=canonical]", "href").unwrap_or_default();
+    // Upcoming streams also have a watch page, so the live flag must be checked
+    let living = canonical.contains("/watch?v=") && html.contains(r#""isLiveNow":true"#);
+
+    let cover = select("meta[property='og:image']", "content").unwrap_or_default();
+    // The page of an offline channel has no author, but the channel name as title
+    let streamer = select("span[itemprop=author] link[itemprop=name]", "content")
+        .or_else(|| select("meta[property='og:title']", "content").filter(|_| !living))
+        .unwrap_or_else(|| channel_id.to_string());
+
+    LiveStream {
+        platform: LivePlatform::Youtube,
+        room_id: channel_id.to_string(),
+        streamer,
+        streamer_url: channel_url.clone(),
+        title: select("meta[name=title]", "content").unwrap_or_default(),
+        url: if living { canonical } else { channel_url },
+        living,
+        online: None,
+        area: None,
+        // Keep the stream thumbnail for the notification after the stream ends
+        keyframe: living.then(|| cover.clone()),
+        cover,
+        start_time: MATCH_START_TIMESTAMP
+            .captures(html)
+            .and_then(|caps| chrono::DateTime::parse_from_rfc3339(&caps[1]).ok())
+            .map(|time| time.timestamp()),
+    }
+}
+
+async fn watch_and_response(ctx: EventWatcher<()>) -> anyhow::Result<()> {
+    let client = ctx.data.youtube_client();
+
+    let channels: Vec<String> = ctx.event_pool()?;
+    let mut streams = Vec::with_capacity(channels.len());
+    for channel_id in channels {
+        match get_channel_live(&client, &channel_id).await {
+            Ok(stream) => streams.push((channel_id, stream)),
+            // Keep the other channels working when one of them fails
+            Err(err) => {
+                metrics::record_upstream_error(&err);
+                tracing::error!("[YoutubeLive] fail to fetch channel {channel_id}: {err}")
+            }
+        }
+    }
+    live::process_live_streams(&ctx, streams).await
+}
+
+#[test]
+fn test_parse_live_page() {
+    let living = r#"<html><head>
+        <link rel="canonical" href="https://www.youtube.com/watch?v=abc">
+        <meta name="title" content="Live &amp; Chat">
+        <meta property="og:image" content="https://i.ytimg.com/vi/abc/maxresdefault_live.jpg">
+        </head><body>
+        <span itemprop="author"><link itemprop="name" content="Alice"></span>
+        <script>{"isLiveNow":true,"startTimestamp":"2024-01-01T00:00:00+00:00"}</script>
+        </body></html>"#;
+    let stream = parse_live_page("UC1", living);
+    assert!(stream.living);
+    assert_eq!(stream.streamer, "Alice");
+    assert_eq!(stream.title, "Live & Chat");
+    assert_eq!(stream.url, "https://www.youtube.com/watch?v=abc");
+    assert_eq!(stream.start_time, Some(1704067200));
+    assert_eq!(
+        stream.cover,
+        "https://i.ytimg.com/vi/abc/maxresdefault_live.jpg"
+    );
+
+    let upcoming = living.replace(r#""isLiveNow":true"#, r#""isLiveNow":false"#);
+    assert!(!parse_live_page("UC1", &upcoming).living);
+
+    let offline = r#"<html><head>
+        <link rel="canonical" href="https://www.youtube.com/channel/UC1">
+        <meta property="og:title" content="Alice">
+        </head></html>"#;
+    let stream = parse_live_page("UC1", offline);
+    assert!(!stream.living);
+    assert_eq!(stream.streamer, "Alice");
+    assert_eq!(stream.url, "https://www.youtube.com/channel/UC1");
+}
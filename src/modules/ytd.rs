@@ -1,25 +1,184 @@
-use crate::config::Config;
+use crate::config::{Config, OversizePolicy};
 use crate::helper::Html;
 use anyhow::Context;
 use serde::Deserialize;
-use std::path::PathBuf;
+use std::collections::BTreeSet;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use teloxide::types::InputFile;
+use tempfile::TempDir;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
 use tokio::process;
-use walkdir::WalkDir;
+use tokio::sync::watch;
 
-use super::video_dl::VideoDownloader;
+use super::video_dl::{DownloadOptions, MediaFile, MediaKind, VideoDownloader};
+
+/// Prefix to distinguish the progress line from other yt-dlp output
+const PROGRESS_PREFIX: &str = "[tg-maid-progress]";
+/// Prefix to distinguish the downloaded file path from other yt-dlp output
+const FILEPATH_PREFIX: &str = "[tg-maid-filepath]";
+
+/// Download progress reported by yt-dlp
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DownloadProgress {
+    pub percent: f32,
+    pub speed: String,
+    pub eta: String,
+}
+
+impl DownloadProgress {
+    /// Template for the yt-dlp `--progress-template` argument, the output line can be parsed by
+    /// [`DownloadProgress::parse_line`].
+    pub fn template() -> String {
+        format!(
+            "download:{PROGRESS_PREFIX}%(progress._percent_str)s|%(progress._speed_str)s|%(progress._eta_str)s"
+        )
+    }
+
+    pub fn parse_line(line: &str) -> Option<Self> {
+        let mut fields = line.trim().strip_prefix(PROGRESS_PREFIX)?.split('|');
+        let percent = fields
+            .next()?
+            .trim()
+            .trim_end_matches('%')
+            .parse::<f32>()
+            .ok()?;
+        let speed = fields.next()?.trim().to_string();
+        let eta = fields.next()?.trim().to_string();
+
+        Some(Self {
+            percent,
+            speed,
+            eta,
+        })
+    }
+}
+
+impl Display for DownloadProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const BAR_WIDTH: usize = 20;
+        let filled = ((self.percent / 100.0) * BAR_WIDTH as f32).round() as usize;
+        let filled = filled.min(BAR_WIDTH);
+        write!(
+            f,
+            "[{}{}] {:.1}%\nSpeed: {}\nETA: {}",
+            "#".repeat(filled),
+            "-".repeat(BAR_WIDTH - filled),
+            self.percent,
+            self.speed,
+            self.eta
+        )
+    }
+}
+
+/// Format seconds into `H:MM:SS` or `M:SS`
+fn format_duration(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    let (hours, minutes, seconds) = (secs / 3600, secs % 3600 / 60, secs % 60);
+    if hours > 0 {
+        format!("{hours}:{minutes:02}:{seconds:02}")
+    } else {
+        format!("{minutes}:{seconds:02}")
+    }
+}
+
+/// Media kind to be downloaded by yt-dlp
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadMode {
+    /// Video with audio track in mp4 format if possible
+    #[default]
+    Video,
+    /// Best audio track extracted into mp3 with the thumbnail embedded as cover art
+    Audio,
+}
+
+impl DownloadMode {
+    /// Build the yt-dlp `--format` selector. Every format must be smaller than `limit_mb`, which is
+    /// the Telegram max file limit for bot. When `allow_oversize` is true, a larger video will be
+    /// selected as the last resort, and the caller should fit it into the limit after download.
+    pub fn format_selector(self, limit_mb: u64, allow_oversize: bool) -> String {
+        let size = [
+            format!("[filesize<{limit_mb}M]"),
+            format!("[filesize_approx<{limit_mb}M]"),
+        ];
+        match self {
+            Self::Video => {
+                // Select video with mp4 format and size lower than the limit
+                const QUALITY: [&str; 4] = ["b", "w", "b*", "w*"];
+                const EXT: [&str; 2] = ["[ext=mp4]", ""];
+                let size = &size;
+                let mut selector = QUALITY
+                    .iter()
+                    .flat_map(move |qua| {
+                        EXT.iter().flat_map(move |ext| {
+                            // Use the yt-dlp format to select video will returns video only & audio only
+                            // format for BiliBili video.
+                            //
+                            // +wa means merge this video with the worst audio
+                            size.iter().map(move |size| format!("{qua}{ext}{size}+wa"))
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                if allow_oversize {
+                    // There is no need to download a video with higher resolution than 1080p when
+                    // it will be transcoded or split later.
+                    selector.push("bv*[height<=1080]+ba/b".to_string());
+                }
+                selector.join("/")
+            }
+            Self::Audio => size
+                .iter()
+                .map(|size| format!("ba{size}"))
+                .collect::<Vec<_>>()
+                .join("/"),
+        }
+    }
+
+    /// Extra arguments passed to yt-dlp when downloading
+    fn download_args(self) -> &'static [&'static str] {
+        match self {
+            Self::Video => &[],
+            Self::Audio => &[
+                "--extract-audio",
+                "--audio-format",
+                "mp3",
+                "--embed-thumbnail",
+                "--embed-metadata",
+                // Telegram only accept JPEG as audio thumbnail
+                "--convert-thumbnails",
+                "jpg",
+            ],
+        }
+    }
+}
+
+/// Caption template for bilibili and YouTube videos
+const DESCRIPTIVE_CAPTION_TEMPLATE: &str =
+    "视频：{title_link}\n上传者：{uploader_link}\n简介：{description}...";
+/// Caption template for the platforms that have no template configured
+const GENERIC_CAPTION_TEMPLATE: &str =
+    "视频：{title_link}\n上传者：{uploader_link}\n时长：{duration} | 播放：{view_count}";
+/// Telegram allows 1024 characters in a caption, keep some room for the `[1/N]` of split parts
+const CAPTION_MAX_CHARS: usize = 1000;
 
 #[derive(Deserialize, Debug)]
 pub struct YtdlpVideo {
     pub id: String,
+    #[serde(default)]
     pub uploader: String,
+    #[serde(default)]
     pub uploader_id: String,
+    pub uploader_url: Option<String>,
+    #[serde(default)]
     pub description: String,
     pub fulltitle: String,
     pub webpage_url: String,
     pub webpage_url_domain: String,
-    pub width: u32,
-    pub height: u32,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub duration: Option<f64>,
+    pub view_count: Option<u64>,
     pub filename: String,
     pub is_live: Option<bool>,
     pub thumbnail: String,
@@ -28,40 +187,57 @@ pub struct YtdlpVideo {
     pub thumbnail_filepath: PathBuf,
     #[serde(skip)]
     pub maybe_playlist: bool,
+    #[serde(skip)]
+    pub mode: DownloadMode,
+    /// Numbered parts of the video when it is split to fit the upload limit
+    #[serde(skip)]
+    pub parts: Vec<PathBuf>,
+    #[serde(skip)]
+    workdir: Option<TempDir>,
 }
 
 impl YtdlpVideo {
     pub async fn dl_from_url(url: &str) -> anyhow::Result<Self> {
-        // Select video with mp4 format and size lower than 50M
-        const QUALITY: [&str; 4] = ["b", "w", "b*", "w*"];
-        const EXT: [&str; 2] = ["[ext=mp4]", ""];
-        const SIZE: [&str; 2] = ["[filesize<50M]", "[filesize_approx<50M]"];
-        let video_format = QUALITY
-            .iter()
-            .flat_map(move |qua| {
-                EXT.iter().flat_map(move |ext| {
-                    // Use the yt-dlp format to select video will returns video only & audio only
-                    // format for BiliBili video.
-                    //
-                    // +wa means merge this video with the worst audio
-                    SIZE.iter().map(move |size| format!("{qua}{ext}{size}+wa"))
-                })
-            })
-            .collect::<Vec<_>>()
-            .join("/");
-
-        use which::which;
-        let ytdlp = which("yt-dlp").expect("can not found yt-dlp program");
-        let mut info = process::Command::new(ytdlp.to_str().unwrap());
-        info.arg(url)
+        let DownloadOptions {
+            mode,
+            playlist_item,
+            progress,
+        } = DownloadOptions::default();
+        Self::dl_from_url_with_progress(url, mode, playlist_item, progress).await
+    }
+
+    /// Download media from the given URL, and report the download progress through the `progress`
+    /// channel while yt-dlp is running. When `playlist_item` is given, the entry at that 1-based
+    /// index of the playlist is downloaded instead of the default one.
+    ///
+    /// Every download runs in its own temporary directory, which is deleted when the returned value
+    /// is dropped or cleaned, or immediately when the download fails.
+    pub async fn dl_from_url_with_progress(
+        url: &str,
+        mode: DownloadMode,
+        playlist_item: Option<usize>,
+        progress: watch::Sender<DownloadProgress>,
+    ) -> anyhow::Result<Self> {
+        let config = Config::get_global_config();
+        let limit_mb = config.upload_limit_mb();
+        let allow_oversize = config.yt_dlp.oversize != OversizePolicy::Fail;
+        let video_format = mode.format_selector(limit_mb, allow_oversize);
+        let playlist_args = match playlist_item {
+            Some(item) => vec![
+                "--yes-playlist".to_string(),
+                "--playlist-items".to_string(),
+                item.to_string(),
+            ],
+            None => vec!["--no-playlist".to_string()],
+        };
+
+        let info = ytdlp_command()
+            .arg(url)
             .arg("--format")
             .arg(&video_format)
             .arg("--restrict-filenames")
-            .arg("-j");
-        if let Some(proxy_url) = Config::get_global_config().proxy.yt_dlp() {
-            info.arg("--proxy").arg(proxy_url);
-        }
-        let info = info
+            .args(&playlist_args)
+            .arg("-j")
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .output()
@@ -69,128 +245,638 @@ impl YtdlpVideo {
         if !info.status.success() {
             let err = String::from_utf8_lossy(&info.stderr);
             if err.contains("Requested format is not available") {
-                anyhow::bail!(
-                    "The requested video has no mp4 format (required for Telegram preview)\
-                    or is larger than 50MB (Telegram max file limit for bot)"
-                )
+                match mode {
+                    DownloadMode::Video => anyhow::bail!(
+                        "The requested video has no mp4 format (required for Telegram preview)\
+                        or is larger than {limit_mb}MB (Telegram max file limit for bot)"
+                    ),
+                    DownloadMode::Audio => anyhow::bail!(
+                        "The requested audio is larger than {limit_mb}MB (Telegram max file limit for bot)"
+                    ),
+                }
             }
             anyhow::bail!("{}", err)
         }
 
+        if info.stdout.trim_ascii().is_empty() {
+            anyhow::bail!("Nothing to download, the playlist item might not exist");
+        }
         let mut info: Self = serde_json::from_slice(&info.stdout)?;
         if let Some(true) = info.is_live {
             anyhow::bail!("Downloading livestream is not allowed");
         }
 
-        let result = process::Command::new("yt-dlp")
+        let workdir = tempfile::Builder::new()
+            .prefix("tg-maid-ytdlp-")
+            .tempdir()
+            .with_context(|| "fail to create working directory for yt-dlp")?;
+
+        let mut child = ytdlp_command()
+            .current_dir(workdir.path())
             .arg(url)
             .arg("--format")
             .arg(&video_format)
             .arg("--write-thumbnail")
             .arg("--restrict-filenames")
-            .arg("--no-progress")
-            .arg("--no-playlist")
+            .arg("--newline")
+            .arg("--progress")
+            .arg("--progress-template")
+            .arg(DownloadProgress::template())
+            .arg("--print")
+            .arg(format!("after_move:{FILEPATH_PREFIX}%(filepath)s"))
+            .args(&playlist_args)
+            .args(mode.download_args())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
-            .output()
-            .await?;
-        if !result.status.success() {
-            anyhow::bail!("{}", String::from_utf8_lossy(&result.stderr));
+            .kill_on_drop(true)
+            .spawn()?;
+
+        // `--print` implies `--quiet`, which moves the progress output into stderr. Consume both of
+        // them at the same time, or yt-dlp will be blocked when one of the pipe buffer is full.
+        let stdout = child.stdout.take().expect("[ytdlp] stdout must be piped");
+        let stderr = child.stderr.take().expect("[ytdlp] stderr must be piped");
+        let (stdout, stderr) = tokio::join!(
+            consume_output(stdout, &progress),
+            consume_output(stderr, &progress),
+        );
+        let (stdout, stderr) = (stdout?, stderr?);
+
+        let status = child.wait().await?;
+        if !status.success() {
+            anyhow::bail!("{}", stderr.join("\n"));
         }
 
-        let video_path = PathBuf::from(&info.filename);
+        let Some(video_path) = stdout
+            .iter()
+            .find_map(|line| line.strip_prefix(FILEPATH_PREFIX))
+            .map(|path| workdir.path().join(path))
+        else {
+            anyhow::bail!("yt-dlp exit successfully but doesn't report the downloaded file path");
+        };
         match tokio::fs::try_exists(&video_path).await {
             Ok(true) => (),
             _ => {
                 anyhow::bail!("No video file found, this might happen when yt-dlp download fail but exit with no error");
             }
         }
-        let Some(ext) = video_path.extension().map(|a| a.to_str().unwrap()) else {
-            anyhow::bail!("No extension found for this video, this should not be happened");
-        };
-        let filename = video_path
-            .file_name()
-            .expect("[ytdlp] must have filename")
-            .to_str()
-            .expect("[ytdlp] must be UTF-8")
-            .strip_suffix(&format!(".{ext}"))
-            .expect("[ytdlp] must have extension");
-
-        let thumbnail = WalkDir::new(".")
-            .into_iter()
-            .filter_map(|p| p.ok())
-            .filter(|p| p.path().extension().is_some())
-            .filter(|p| {
-                ["jpg", "png", "webp"].contains(&p.path().extension().unwrap().to_str().unwrap())
-            })
-            .find(|p| p.file_name().to_str().unwrap().contains(filename))
-            .map(|x| x.path().to_path_buf());
-
-        if thumbnail.is_none() {
-            info.clean().await?;
+
+        // Working directory is owned by this download, so any image inside is the thumbnail
+        let Some(thumbnail) = find_thumbnail(workdir.path()).await? else {
             anyhow::bail!("No thumbnail for this video")
-        }
+        };
 
+        info.filename = video_path.to_string_lossy().to_string();
         info.maybe_playlist = info.id.ends_with("_p1");
-        info.thumbnail_filepath = thumbnail.unwrap();
+        info.mode = mode;
+        info.thumbnail_filepath = thumbnail;
+        info.workdir = Some(workdir);
 
         Ok(info)
     }
 
+    /// Build the caption from the template configured for the video domain in
+    /// `[yt_dlp.caption]`, fallback to the `default` template and then the built-in one.
     pub fn as_tg_video_caption(&self) -> String {
+        let config = Config::get_global_config();
+        let templates = &config.yt_dlp.caption;
+        let template = templates
+            .get(&self.webpage_url_domain)
+            .or_else(|| templates.get("default"))
+            .map(String::as_str)
+            .unwrap_or(match self.webpage_url_domain.as_str() {
+                "bilibili.com" | "youtube.com" => DESCRIPTIVE_CAPTION_TEMPLATE,
+                _ => GENERIC_CAPTION_TEMPLATE,
+            });
+        self.render_caption(template)
+    }
+
+    /// Replace the `{placeholder}` in the template with the HTML escaped video metadata. Unknown
+    /// placeholders are kept as is, and the caption is cut to fit the Telegram limit.
+    pub fn render_caption(&self, template: &str) -> String {
+        let mut caption = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            caption.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let Some(end) = rest.find('}') else {
+                break;
+            };
+            match self.caption_field(&rest[1..end]) {
+                Some(value) => caption.push_str(&value),
+                None => caption.push_str(&rest[..=end]),
+            }
+            rest = &rest[end + 1..];
+        }
+        caption.push_str(rest);
+        Html::truncate(&caption, CAPTION_MAX_CHARS)
+    }
+
+    fn caption_field(&self, name: &str) -> Option<String> {
+        use teloxide::utils::html::escape;
+
+        let value = match name {
+            "title" => escape(&self.fulltitle),
+            "url" => escape(&self.webpage_url),
+            "title_link" => Html::a(&self.webpage_url, &escape(&self.fulltitle)),
+            "uploader" => escape(&self.uploader),
+            "uploader_url" => escape(&self.uploader_profile_url().unwrap_or_default()),
+            "uploader_link" => match self.uploader_profile_url() {
+                Some(link) => Html::a(&link, &escape(&self.uploader)),
+                None => escape(&self.uploader),
+            },
+            "description" => escape(&self.description.chars().take(100).collect::<String>()),
+            "duration" => self
+                .duration
+                .map(format_duration)
+                .unwrap_or_else(|| "N/A".to_string()),
+            "view_count" => self
+                .view_count
+                .map(|count| count.to_string())
+                .unwrap_or_else(|| "N/A".to_string()),
+            "domain" => escape(&self.webpage_url_domain),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    fn uploader_profile_url(&self) -> Option<String> {
+        if let Some(url) = &self.uploader_url {
+            return Some(url.clone());
+        }
+        if self.uploader_id.is_empty() {
+            return None;
+        }
         match self.webpage_url_domain.as_str() {
-            "bilibili.com" => {
-                let upload_profile_link =
-                    format!("https://space.bilibili.com/{}", self.uploader_id);
-                let uploader = Html::a(&upload_profile_link, &self.uploader);
-                let video_title = Html::a(&self.webpage_url, &self.fulltitle);
-                format!(
-                    "视频：{}\n\
-                上传者：{}\n\
-                简介：{}...
-                ",
-                    video_title,
-                    uploader,
-                    self.description.chars().take(100).collect::<String>()
+            "bilibili.com" => Some(format!("https://space.bilibili.com/{}", self.uploader_id)),
+            "youtube.com" => Some(format!("https://www.youtube.com/{}", self.uploader_id)),
+            _ => None,
+        }
+    }
+
+    /// Make sure the downloaded video fit into the upload limit by transcoding or splitting it
+    /// according to the given policy.
+    pub async fn fit_upload_limit(
+        &mut self,
+        limit_mb: u64,
+        policy: OversizePolicy,
+    ) -> anyhow::Result<()> {
+        let limit = limit_mb * 1024 * 1024;
+        let size = tokio::fs::metadata(&self.filename).await?.len();
+        if size <= limit {
+            return Ok(());
+        }
+
+        let Some(duration) = self.duration.filter(|secs| *secs > 0.0) else {
+            anyhow::bail!("Unknown video duration, can't fit the video into {limit_mb}MB");
+        };
+        let workdir = self
+            .workdir
+            .as_ref()
+            .expect("[ytdlp] downloaded video must have working directory")
+            .path()
+            .to_path_buf();
+
+        match policy {
+            OversizePolicy::Fail => {
+                anyhow::bail!(
+                    "The video is larger than {limit_mb}MB (Telegram max file limit for bot)"
                 )
             }
-            "youtube.com" => {
-                let upload_profile_link = format!("https://www.youtube.com/{}", self.uploader_id);
-                let uploader = Html::a(&upload_profile_link, &self.uploader);
-                let video_title = Html::a(&self.webpage_url, &self.fulltitle);
-                format!(
-                    "视频：{}\n\
-                上传者：{}\n\
-                简介：{}...
-                ",
-                    video_title,
-                    uploader,
-                    self.description.chars().take(100).collect::<String>()
-                )
+            OversizePolicy::Transcode => {
+                let Some((bitrate, height)) = transcode_target(duration, limit) else {
+                    anyhow::bail!("The video is too long to be transcoded into {limit_mb}MB");
+                };
+                let output = workdir.join("transcoded.mp4").to_string_lossy().to_string();
+                run_ffmpeg(&[
+                    "-y",
+                    "-i",
+                    &self.filename,
+                    "-c:v",
+                    "libx264",
+                    "-b:v",
+                    &bitrate.to_string(),
+                    "-maxrate",
+                    &bitrate.to_string(),
+                    "-bufsize",
+                    &(bitrate * 2).to_string(),
+                    "-vf",
+                    &format!("scale=-2:'min({height},ih)'"),
+                    "-c:a",
+                    "aac",
+                    "-b:a",
+                    &TRANSCODE_AUDIO_BITRATE.to_string(),
+                    "-movflags",
+                    "+faststart",
+                    &output,
+                ])
+                .await?;
+
+                if tokio::fs::metadata(&output).await?.len() > limit {
+                    anyhow::bail!("The transcoded video is still larger than {limit_mb}MB");
+                }
+                self.filename = output;
+            }
+            OversizePolicy::Split => {
+                let segment = split_segment_seconds(duration, size, limit);
+                // The downloaded files share the working directory, keep the parts on their own
+                let parts_dir = workdir.join("parts");
+                tokio::fs::create_dir_all(&parts_dir).await?;
+                let pattern = parts_dir.join("part%03d.mp4");
+                run_ffmpeg(&[
+                    "-y",
+                    "-i",
+                    &self.filename,
+                    "-map",
+                    "0",
+                    "-c",
+                    "copy",
+                    "-f",
+                    "segment",
+                    "-segment_time",
+                    &format!("{segment:.0}"),
+                    "-reset_timestamps",
+                    "1",
+                    &pattern.to_string_lossy(),
+                ])
+                .await?;
+
+                let mut parts = Vec::new();
+                let mut entries = tokio::fs::read_dir(&parts_dir).await?;
+                while let Some(entry) = entries.next_entry().await? {
+                    if entry.metadata().await?.len() > limit {
+                        anyhow::bail!(
+                            "Fail to split the video into parts smaller than {limit_mb}MB"
+                        );
+                    }
+                    parts.push(entry.path());
+                }
+                parts.sort();
+                self.parts = parts;
             }
-            _ => "Unsupported platform".to_string(),
         }
+
+        Ok(())
     }
 
     pub async fn clean(self) -> anyhow::Result<()> {
-        tokio::fs::remove_file(self.filename)
-            .await
-            .with_context(|| "fail to delete video")?;
-        tokio::fs::remove_file(self.thumbnail_filepath)
-            .await
-            .with_context(|| "fail to delete thumbnail")?;
+        if let Some(workdir) = self.workdir {
+            tokio::task::spawn_blocking(move || workdir.close())
+                .await?
+                .with_context(|| "fail to delete yt-dlp working directory")?;
+        }
         Ok(())
     }
 }
 
+/// Audio bitrate in bit/s used when transcoding oversized video
+const TRANSCODE_AUDIO_BITRATE: u64 = 128_000;
+
+/// Calculate the video bitrate in bit/s and the max height to transcode a video with the given
+/// duration into `limit` bytes. Return `None` if the video is too long to have a watchable quality.
+fn transcode_target(duration: f64, limit: u64) -> Option<(u64, u32)> {
+    // Keep 10% room for the container overhead and the bitrate fluctuation
+    let total = (limit as f64 * 8.0 * 0.9 / duration) as u64;
+    let bitrate = total.checked_sub(TRANSCODE_AUDIO_BITRATE)?;
+    let height = match bitrate {
+        2_500_000.. => 1080,
+        1_200_000.. => 720,
+        600_000.. => 480,
+        150_000.. => 360,
+        _ => return None,
+    };
+    Some((bitrate, height))
+}
+
+/// Calculate the segment length in seconds to split a `size` bytes video into parts smaller than
+/// `limit` bytes.
+fn split_segment_seconds(duration: f64, size: u64, limit: u64) -> f64 {
+    // ffmpeg can only split at key frames, keep 20% room for it
+    duration * (limit as f64 * 0.8) / size as f64
+}
+
+async fn run_ffmpeg(args: &[&str]) -> anyhow::Result<()> {
+    use which::which;
+    let ffmpeg = which("ffmpeg").with_context(|| "can not found ffmpeg program")?;
+    let result = process::Command::new(ffmpeg)
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .output()
+        .await?;
+    if !result.status.success() {
+        anyhow::bail!(
+            "ffmpeg exit with error: {}",
+            String::from_utf8_lossy(&result.stderr)
+        );
+    }
+    Ok(())
+}
+
+/// Entries of a playlist, or parts of a multi-part video
+#[derive(Deserialize, Debug)]
+pub struct YtdlpPlaylist {
+    pub title: Option<String>,
+    /// Empty when the URL point to a single video
+    #[serde(default)]
+    pub entries: Vec<YtdlpPlaylistEntry>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct YtdlpPlaylistEntry {
+    pub title: Option<String>,
+}
+
+impl YtdlpPlaylist {
+    /// List the playlist entries without resolving each of them, which is fast enough to be called
+    /// before every download.
+    pub async fn fetch(url: &str) -> anyhow::Result<Self> {
+        let output = ytdlp_command()
+            .arg(url)
+            .arg("--yes-playlist")
+            .arg("--flat-playlist")
+            .arg("-J")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .output()
+            .await?;
+        if !output.status.success() {
+            anyhow::bail!("{}", String::from_utf8_lossy(&output.stderr));
+        }
+
+        let playlist = serde_json::from_slice(&output.stdout)?;
+        Ok(playlist)
+    }
+
+    pub fn is_multi_part(&self) -> bool {
+        self.entries.len() > 1
+    }
+}
+
+/// Return true if the URL links to a media that can be downloaded automatically in the chats that
+/// enabled auto download: bilibili videos, YouTube shorts and tweets.
+pub fn is_auto_download_url(url: &reqwest::Url) -> bool {
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+    let host = host
+        .trim_start_matches("www.")
+        .trim_start_matches("m.")
+        .trim_start_matches("mobile.");
+    let path = url.path();
+    match host {
+        "bilibili.com" => path.starts_with("/video/"),
+        "b23.tv" => path.len() > 1,
+        "youtube.com" => path.starts_with("/shorts/"),
+        "twitter.com" | "x.com" | "fxtwitter.com" | "vxtwitter.com" => path.contains("/status/"),
+        _ => false,
+    }
+}
+
+/// Parse the playlist selection argument like `p3`, `p3-5` or `p1,4-6` into a sorted list of
+/// 1-based playlist index. Return `None` if the argument is not a playlist selection, and error if
+/// it selects more than `max_items` entries.
+pub fn parse_playlist_items(arg: &str, max_items: usize) -> anyhow::Result<Option<Vec<usize>>> {
+    let Some(ranges) = arg.strip_prefix(['p', 'P']) else {
+        return Ok(None);
+    };
+    let mut items = BTreeSet::new();
+    for range in ranges.split(',') {
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => match (start.parse::<usize>(), end.parse::<usize>()) {
+                (Ok(start), Ok(end)) => (start, end),
+                _ => return Ok(None),
+            },
+            None => match range.parse::<usize>() {
+                Ok(item) => (item, item),
+                Err(_) => return Ok(None),
+            },
+        };
+        if start == 0 || start > end {
+            return Ok(None);
+        }
+        // Check before collecting, as the range can be arbitrarily large
+        if end - start >= max_items {
+            anyhow::bail!("You can download at most {max_items} items at once");
+        }
+        items.extend(start..=end);
+        if items.len() > max_items {
+            anyhow::bail!("You can download at most {max_items} items at once");
+        }
+    }
+    Ok(Some(items.into_iter().collect()))
+}
+
+fn ytdlp_command() -> process::Command {
+    use which::which;
+    let ytdlp = which("yt-dlp").expect("can not found yt-dlp program");
+    let mut command = process::Command::new(ytdlp);
+    if let Some(proxy_url) = Config::get_global_config().proxy.yt_dlp() {
+        command.arg("--proxy").arg(proxy_url);
+    }
+    command
+}
+
+/// Read the yt-dlp output line by line, report the progress lines to the `progress` channel and
+/// return the rest of the lines.
+async fn consume_output(
+    output: impl AsyncRead + Unpin,
+    progress: &watch::Sender<DownloadProgress>,
+) -> std::io::Result<Vec<String>> {
+    let mut lines = BufReader::new(output).lines();
+    let mut rest = Vec::new();
+    while let Some(line) = lines.next_line().await? {
+        match DownloadProgress::parse_line(&line) {
+            Some(current) => {
+                progress.send_replace(current);
+            }
+            None => rest.push(line),
+        }
+    }
+    Ok(rest)
+}
+
+async fn find_thumbnail(dir: &Path) -> anyhow::Result<Option<PathBuf>> {
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let is_image = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| ["jpg", "png", "webp"].contains(&ext));
+        if is_image {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
 impl VideoDownloader for YtdlpVideo {
-    async fn download_from_url(u: &str) -> anyhow::Result<Self> {
-        Self::dl_from_url(u).await
+    async fn download_from_url(u: &str, options: DownloadOptions) -> anyhow::Result<Self> {
+        Self::dl_from_url_with_progress(u, options.mode, options.playlist_item, options.progress)
+            .await
     }
 
     fn provide_caption(&self) -> String {
         self.as_tg_video_caption()
     }
+
+    fn media_files(&self) -> Vec<MediaFile> {
+        if !self.parts.is_empty() {
+            return self
+                .parts
+                .iter()
+                .map(|part| MediaFile::new(InputFile::file(part), MediaKind::Video))
+                .collect();
+        }
+
+        let kind = match self.mode {
+            DownloadMode::Video => MediaKind::Video,
+            DownloadMode::Audio => MediaKind::Audio,
+        };
+        let mut media = MediaFile::new(InputFile::file(&self.filename), kind);
+        media.width = self.width;
+        media.height = self.height;
+        media.duration = self.duration.map(|secs| secs.round() as u32);
+        media.thumbnail = Some(InputFile::file(&self.thumbnail_filepath));
+        if kind == MediaKind::Audio {
+            media.title = Some(self.fulltitle.clone());
+            media.performer = Some(self.uploader.clone());
+        }
+        vec![media]
+    }
+
+    async fn fit_upload_limit(
+        &mut self,
+        limit_mb: u64,
+        policy: OversizePolicy,
+    ) -> anyhow::Result<()> {
+        if self.mode == DownloadMode::Audio {
+            return Ok(());
+        }
+        YtdlpVideo::fit_upload_limit(self, limit_mb, policy).await
+    }
+
+    fn maybe_playlist(&self) -> bool {
+        self.maybe_playlist
+    }
+
+    async fn clean(self) -> anyhow::Result<()> {
+        YtdlpVideo::clean(self).await
+    }
+}
+
+#[test]
+fn test_download_mode_format_selector() {
+    assert_eq!(
+        DownloadMode::Audio.format_selector(50, true),
+        "ba[filesize<50M]/ba[filesize_approx<50M]"
+    );
+    let video = DownloadMode::Video.format_selector(2000, false);
+    assert!(video.starts_with("b[ext=mp4][filesize<2000M]+wa/"));
+    assert!(video.ends_with("w*[filesize_approx<2000M]+wa"));
+    assert!(DownloadMode::Video
+        .format_selector(50, true)
+        .ends_with("/bv*[height<=1080]+ba/b"));
+}
+
+#[test]
+fn test_transcode_target() {
+    // A 10 minutes video squeezed into 50MB has about 500Kbps for the video track
+    let (bitrate, height) = transcode_target(600.0, 50 * 1024 * 1024).unwrap();
+    assert!(bitrate < 50 * 1024 * 1024 * 8 / 600);
+    assert_eq!(height, 360);
+
+    let (_, height) = transcode_target(60.0, 50 * 1024 * 1024).unwrap();
+    assert_eq!(height, 1080);
+
+    assert!(transcode_target(36000.0, 50 * 1024 * 1024).is_none());
+}
+
+#[test]
+fn test_split_segment_seconds() {
+    // 120MB video in 10 minutes split into 50MB parts
+    let seconds = split_segment_seconds(600.0, 120 * 1024 * 1024, 50 * 1024 * 1024);
+    assert!((seconds - 200.0).abs() < 1.0);
+}
+
+#[test]
+fn test_render_caption() {
+    let video: YtdlpVideo = serde_json::from_value(serde_json::json!({
+        "id": "1",
+        "uploader": "Alice & Bob",
+        "uploader_id": "alice",
+        "fulltitle": "<Hello>",
+        "webpage_url": "https://x.com/alice/status/1",
+        "webpage_url_domain": "x.com",
+        "duration": 3725.2,
+        "view_count": 42,
+        "filename": "a.mp4",
+        "thumbnail": "",
+    }))
+    .unwrap();
+
+    assert_eq!(
+        video.render_caption("{title_link} by {uploader_link}"),
+        "<a href=\"https://x.com/alice/status/1\">&lt;Hello&gt;</a> by Alice &amp; Bob"
+    );
+    assert_eq!(
+        video.render_caption("{duration} | {view_count} | {description}| {unknown} {"),
+        "1:02:05 | 42 | | {unknown} {"
+    );
+    assert_eq!(format_duration(61.0), "1:01");
+
+    let caption = video.render_caption(&format!("{}{{title}}", "字".repeat(CAPTION_MAX_CHARS)));
+    assert_eq!(caption.chars().count(), CAPTION_MAX_CHARS);
+    assert!(caption.ends_with("字…"));
+}
+
+#[test]
+fn test_is_auto_download_url() {
+    let check = |url: &str| is_auto_download_url(&reqwest::Url::parse(url).unwrap());
+    assert!(check("https://www.bilibili.com/video/BV1JB4y1s7Dk/"));
+    assert!(check("https://m.bilibili.com/video/BV1JB4y1s7Dk"));
+    assert!(check("https://b23.tv/abcdef"));
+    assert!(check("https://youtube.com/shorts/abcdef"));
+    assert!(check("https://x.com/alice/status/1"));
+    assert!(check("https://mobile.twitter.com/alice/status/1"));
+
+    assert!(!check("https://www.bilibili.com/read/cv1"));
+    assert!(!check("https://b23.tv/"));
+    assert!(!check("https://www.youtube.com/watch?v=abcdef"));
+    assert!(!check("https://x.com/alice"));
+    assert!(!check("https://example.com/video/1"));
+}
+
+#[test]
+fn test_parse_playlist_items() {
+    let parse = |arg| parse_playlist_items(arg, 10).unwrap();
+    assert_eq!(parse("p3"), Some(vec![3]));
+    assert_eq!(parse("p3-5"), Some(vec![3, 4, 5]));
+    assert_eq!(parse("P1,4-5"), Some(vec![1, 4, 5]));
+    assert_eq!(parse("p1,2,1"), Some(vec![1, 2]));
+    assert_eq!(parse("p5,1-3"), Some(vec![1, 2, 3, 5]));
+    assert_eq!(parse("p0"), None);
+    assert_eq!(parse("p5-3"), None);
+    assert_eq!(parse("p"), None);
+    assert_eq!(parse("https://example.com"), None);
+    assert_eq!(parse("p1-10"), Some((1..=10).collect()));
+    assert!(parse_playlist_items("p1-11", 10).is_err());
+    assert!(parse_playlist_items("p1-4000000000", 10).is_err());
+    assert!(parse_playlist_items("p1-8,11-13", 10).is_err());
+}
+
+#[test]
+fn test_parse_download_progress() {
+    let progress =
+        DownloadProgress::parse_line("[tg-maid-progress]  42.3%|   1.20MiB/s|00:13\n").unwrap();
+    assert_eq!(progress.percent, 42.3);
+    assert_eq!(progress.speed, "1.20MiB/s");
+    assert_eq!(progress.eta, "00:13");
+
+    assert!(DownloadProgress::parse_line("[download] Destination: a.mp4").is_none());
+    assert!(DownloadProgress::parse_line("[tg-maid-progress]NA|NA|NA").is_none());
 }
 
 #[tokio::test]